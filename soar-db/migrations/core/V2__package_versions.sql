@@ -0,0 +1,10 @@
+CREATE TABLE package_versions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  package_id INTEGER NOT NULL,
+  version TEXT NOT NULL,
+  checksum TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  installed_path TEXT NOT NULL,
+  installed_date TEXT NOT NULL,
+  FOREIGN KEY (package_id) REFERENCES packages (id)
+);
@@ -0,0 +1,25 @@
+CREATE TABLE sandbox_paths (
+  package_id INTEGER NOT NULL,
+  path TEXT NOT NULL,
+  writable BOOLEAN NOT NULL,
+  FOREIGN KEY (package_id) REFERENCES packages (id)
+);
+
+WITH RECURSIVE split (package_id, writable, path, rest) AS (
+  SELECT package_id, false, '', fs_read || ':' FROM sandbox_rules WHERE fs_read IS NOT NULL
+  UNION ALL
+  SELECT package_id, true, '', fs_write || ':' FROM sandbox_rules WHERE fs_write IS NOT NULL
+  UNION ALL
+  SELECT
+    package_id,
+    writable,
+    substr(rest, 1, instr(rest, ':') - 1),
+    substr(rest, instr(rest, ':') + 1)
+  FROM split
+  WHERE rest <> ''
+)
+INSERT INTO sandbox_paths (package_id, path, writable)
+SELECT package_id, path, writable FROM split WHERE path <> '';
+
+ALTER TABLE sandbox_rules DROP COLUMN fs_read;
+ALTER TABLE sandbox_rules DROP COLUMN fs_write;
@@ -72,5 +72,5 @@ CREATE TABLE packages (
   FOREIGN KEY (collection_id) REFERENCES collections (id),
   FOREIGN KEY (family_id) REFERENCES families (id),
   FOREIGN KEY (icon_id) REFERENCES icons (id),
-  UNIQUE (family_id, pkg_name)
+  UNIQUE (collection_id, family_id, pkg, pkg_name)
 );
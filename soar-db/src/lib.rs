@@ -6,11 +6,11 @@ pub mod core {
 
     embed_migrations!("migrations/core");
 
-    pub fn init_db<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn init_db<P: AsRef<Path>>(path: P) -> Result<Connection, Box<dyn std::error::Error>> {
         let path = path.as_ref();
         let mut conn = Connection::open(path)?;
         migrations::runner().run(&mut conn)?;
-        Ok(())
+        Ok(conn)
     }
 }
 
@@ -22,10 +22,10 @@ pub mod metadata {
 
     embed_migrations!("migrations/metadata");
 
-    pub fn init_db<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn init_db<P: AsRef<Path>>(path: P) -> Result<Connection, Box<dyn std::error::Error>> {
         let path = path.as_ref();
         let mut conn = Connection::open(path)?;
         migrations::runner().run(&mut conn)?;
-        Ok(())
+        Ok(conn)
     }
 }
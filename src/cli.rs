@@ -22,6 +22,15 @@ pub struct Args {
     #[arg(short, long)]
     pub json: bool,
 
+    /// Profile to use instead of the default profile. Takes precedence over SOAR_ROOT,
+    /// SOAR_BIN and SOAR_CACHE
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Work from the cached registry without accessing the network
+    #[arg(long, global = true)]
+    pub offline: bool,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -34,6 +43,168 @@ pub enum SelfAction {
     Uninstall,
 }
 
+#[derive(Subcommand)]
+pub enum ProfileAction {
+    /// List profiles
+    #[clap(name = "list", visible_alias = "ls")]
+    List,
+    /// Add a new profile
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Name of the profile
+        name: String,
+
+        /// Root directory of the profile
+        #[arg(required = true, long, value_hint = ValueHint::DirPath)]
+        root: String,
+
+        /// Directory for binary symlinks [default: <root>/bin]
+        #[arg(required = false, long, value_hint = ValueHint::DirPath)]
+        bin: Option<String>,
+
+        /// Cache directory [default: <root>/cache]
+        #[arg(required = false, long, value_hint = ValueHint::DirPath)]
+        cache: Option<String>,
+
+        /// Directory to install packages into [default: <root>/packages]
+        #[arg(required = false, long, value_hint = ValueHint::DirPath)]
+        packages: Option<String>,
+
+        /// Make this the default profile
+        #[arg(required = false, long)]
+        default: bool,
+    },
+    /// Set the default profile
+    #[command(arg_required_else_help = true)]
+    Default {
+        /// Name of the profile
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RepoAction {
+    /// List repositories
+    #[clap(name = "list", visible_alias = "ls")]
+    List,
+    /// Add a repository after checking that its metadata can be fetched
+    #[command(arg_required_else_help = true)]
+    Add {
+        /// Name of the repository
+        name: String,
+
+        /// URL of the repository, or path to a local repository directory
+        #[arg(value_hint = ValueHint::AnyPath)]
+        url: String,
+
+        /// Mirror of the repository, tried when the URL is unavailable
+        #[arg(required = false, long)]
+        mirror: Vec<String>,
+
+        /// Metadata file of the repository [default: metadata.json]
+        #[arg(required = false, long)]
+        metadata: Option<String>,
+
+        /// Download source of a collection, as <collection>=<url>. Repeat it to add mirrors
+        #[arg(required = false, long, value_parser = parse_source)]
+        source: Vec<(String, String)>,
+    },
+    /// Remove a repository
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Name of the repository
+        name: String,
+    },
+    /// Set the priority of a repository. Higher priority repositories are preferred
+    #[command(arg_required_else_help = true)]
+    SetPriority {
+        /// Name of the repository
+        name: String,
+
+        /// Priority of the repository
+        #[arg(allow_negative_numbers = true)]
+        priority: i32,
+    },
+    /// Enable a repository
+    #[command(arg_required_else_help = true)]
+    Enable {
+        /// Name of the repository
+        name: String,
+    },
+    /// Disable a repository without removing it from config
+    #[command(arg_required_else_help = true)]
+    Disable {
+        /// Name of the repository
+        name: String,
+    },
+    /// Generate repository metadata from a directory of packages
+    #[command(arg_required_else_help = true)]
+    BuildIndex {
+        /// Directory containing the packages
+        #[arg(value_hint = ValueHint::DirPath)]
+        dir: String,
+
+        /// Name of the metadata file to write into the directory
+        #[arg(required = false, long, default_value = "metadata.json")]
+        output: String,
+
+        /// Collection the packages belong to
+        #[arg(required = false, long, default_value = "bin")]
+        collection: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SandboxAction {
+    /// Set sandbox rules for installed packages
+    ///
+    /// Sandboxed AppImages can't mount themselves, so they are extracted to the temporary
+    /// directory every time they run instead.
+    #[command(arg_required_else_help = true)]
+    Set {
+        /// Packages to set the rules for
+        #[arg(required = true)]
+        packages: Vec<String>,
+
+        /// Allow reading from the path
+        #[arg(required = false, long, value_hint = ValueHint::AnyPath)]
+        read: Vec<String>,
+
+        /// Allow reading from and writing to the path
+        #[arg(required = false, long, value_hint = ValueHint::AnyPath)]
+        write: Vec<String>,
+
+        /// Cut off network access
+        #[arg(required = false, long)]
+        no_net: bool,
+    },
+    /// Remove sandbox rules from installed packages
+    #[command(arg_required_else_help = true)]
+    Remove {
+        /// Packages to remove the rules from
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+    /// List sandboxed packages and their rules
+    #[clap(name = "list", visible_alias = "ls")]
+    List,
+    /// Run a command in the sandbox
+    #[command(hide = true)]
+    Exec {
+        #[arg(required = false, long)]
+        read: Vec<String>,
+
+        #[arg(required = false, long)]
+        write: Vec<String>,
+
+        #[arg(required = false, long)]
+        no_net: bool,
+
+        #[arg(required = true, last = true)]
+        command: Vec<String>,
+    },
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Install packages
@@ -63,6 +234,10 @@ pub enum Commands {
         /// Set portable config
         #[arg(required = false, long, num_args = 0..=1, value_hint = ValueHint::AnyPath)]
         portable_config: Option<Option<String>>,
+
+        /// Install even if the checksum is missing or doesn't match
+        #[arg(required = false, long)]
+        allow_checksum_mismatch: bool,
     },
 
     /// Search package
@@ -114,6 +289,59 @@ pub enum Commands {
         /// Packages to update
         #[arg(required = false)]
         packages: Option<Vec<String>>,
+
+        /// Update even if the checksum is missing or doesn't match
+        #[arg(required = false, long)]
+        allow_checksum_mismatch: bool,
+    },
+
+    /// Pin packages to their installed version
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "pin")]
+    Pin {
+        /// Packages to pin
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Unpin packages so they can be updated again
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "unpin")]
+    Unpin {
+        /// Packages to unpin
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Roll back a package to a previously installed version
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "rollback")]
+    Rollback {
+        /// Package to roll back
+        #[arg(required = true)]
+        package: String,
+
+        /// Version to roll back to [default: the previous version]
+        #[arg(required = false, long)]
+        to: Option<String>,
+    },
+
+    /// Disable packages without uninstalling them
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "disable")]
+    Disable {
+        /// Packages to disable
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Enable previously disabled packages
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "enable")]
+    Enable {
+        /// Packages to enable
+        #[arg(required = true)]
+        packages: Vec<String>,
     },
 
     /// Show info about installed packages
@@ -158,6 +386,10 @@ pub enum Commands {
         #[arg(required = false, short, long)]
         yes: bool,
 
+        /// Run even if the checksum is missing or doesn't match
+        #[arg(required = false, long)]
+        allow_checksum_mismatch: bool,
+
         /// Command to execute
         #[arg(required = true, trailing_var_arg = true)]
         command: Vec<String>,
@@ -213,6 +445,30 @@ pub enum Commands {
     #[clap(name = "env")]
     Env,
 
+    /// Manage package sandboxing
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "sandbox")]
+    Sandbox {
+        #[clap(subcommand)]
+        action: SandboxAction,
+    },
+
+    /// Manage profiles
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "profile")]
+    Profile {
+        #[clap(subcommand)]
+        action: ProfileAction,
+    },
+
+    /// Manage repositories
+    #[command(arg_required_else_help = true)]
+    #[clap(name = "repo")]
+    Repo {
+        #[clap(subcommand)]
+        action: RepoAction,
+    },
+
     /// Build
     #[clap(name = "build")]
     Build { files: Vec<String> },
@@ -225,3 +481,11 @@ pub enum Commands {
         action: SelfAction,
     },
 }
+
+fn parse_source(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .filter(|(collection, url)| !collection.is_empty() && !url.is_empty())
+        .map(|(collection, url)| (collection.to_owned(), url.to_owned()))
+        .ok_or_else(|| format!("invalid source '{}', expected <collection>=<url>", value))
+}
@@ -8,12 +8,18 @@ pub static CACHE_PATH: LazyLock<PathBuf> =
 pub static REGISTRY_PATH: LazyLock<PathBuf> = LazyLock::new(|| ROOT_PATH.join("registry"));
 pub static BIN_PATH: LazyLock<PathBuf> =
     LazyLock::new(|| build_path(&CONFIG.soar_bin.clone().unwrap()).unwrap());
+pub static DB_PATH: LazyLock<PathBuf> =
+    LazyLock::new(|| build_path(&CONFIG.soar_db.clone().unwrap()).unwrap());
+pub static CORE_DB_PATH: LazyLock<PathBuf> = LazyLock::new(|| DB_PATH.join("soar.db"));
 pub static INSTALL_TRACK_PATH: LazyLock<PathBuf> = LazyLock::new(|| ROOT_PATH.join("installs"));
-pub static PACKAGES_PATH: LazyLock<PathBuf> = LazyLock::new(|| ROOT_PATH.join("packages"));
+pub static PACKAGES_PATH: LazyLock<PathBuf> =
+    LazyLock::new(|| build_path(&CONFIG.soar_packages.clone().unwrap()).unwrap());
 
 pub const ELF_MAGIC_BYTES: [u8; 4] = [0x7f, 0x45, 0x4c, 0x46];
 pub const APPIMAGE_MAGIC_BYTES: [u8; 4] = [0x41, 0x49, 0x02, 0x00];
 pub const FLATIMAGE_MAGIC_BYTES: [u8; 4] = [0x46, 0x49, 0x01, 0x00];
+pub const ZSTD_MAGIC_BYTES: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+pub const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
 
 pub const CAP_SYS_ADMIN: i32 = 21;
 pub const CAP_MKNOD: i32 = 27;
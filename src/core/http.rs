@@ -0,0 +1,589 @@
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderValue, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+    },
+    redirect::Policy,
+    Certificate, Client, NoProxy, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use tokio::{fs, io::AsyncWriteExt, time::sleep};
+use tracing::{error, warn};
+
+use super::{
+    color::{Color, ColorExt},
+    config::{RepositoryAuth, CONFIG},
+    util::build_path,
+};
+
+/// Number of times a request is attempted before giving up.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry, doubled after every further failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Number of redirects followed before a request fails.
+const MAX_REDIRECTS: usize = 10;
+
+/// HTTP client shared by every request soar makes, set up from the proxy, CA certificate,
+/// timeout and User-Agent options in config.
+pub static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    build_client().unwrap_or_else(|e| {
+        error!("Failed to set up HTTP client: {:#}", e);
+        std::process::exit(1);
+    })
+});
+
+fn build_client() -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(CONFIG.user_agent())
+        .connect_timeout(CONFIG.connect_timeout())
+        .read_timeout(CONFIG.read_timeout())
+        .redirect(redirect_policy(|url| {
+            CONFIG
+                .repository_for_url(url)
+                .filter(|repo| repo.auth.is_some())
+                .map(|repo| repo.name.clone())
+        }));
+
+    if let Some(ref proxy) = CONFIG.proxy {
+        let proxy = Proxy::all(proxy)
+            .with_context(|| format!("Invalid proxy {}", proxy.color(Color::Blue)))?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(ref ca_certificates) = CONFIG.ca_certificates {
+        let path = build_path(ca_certificates)?;
+        let certificates = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|pem| Ok(Certificate::from_pem_bundle(&pem)?))
+            .with_context(|| {
+                format!(
+                    "Failed to load CA certificates from {}",
+                    path.to_string_lossy().color(Color::Blue)
+                )
+            })?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// A response with an unsuccessful status.
+#[derive(Debug)]
+pub struct StatusError {
+    pub url: String,
+    pub status: StatusCode,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Error fetching {} [{}]",
+            self.url.clone().color(Color::Blue),
+            self.status.color(Color::Red)
+        )
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Whether a failed attempt may succeed when tried again.
+///
+/// Connection problems, timeouts, interrupted transfers, server errors and rate limiting are
+/// transient. Anything else, like a missing file or a local I/O error, is not.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    let is_retryable_status =
+        |status: StatusCode| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<StatusError>() {
+            is_retryable_status(e.status)
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            match e.status() {
+                Some(status) => is_retryable_status(status),
+                None => {
+                    e.is_connect()
+                        || e.is_timeout()
+                        || e.is_request()
+                        || e.is_body()
+                        || e.is_decode()
+                }
+            }
+        } else {
+            false
+        }
+    })
+}
+
+/// Runs `attempt` until it succeeds, retrying transient failures with exponential backoff.
+pub async fn retry<T, F, Fut>(url: &str, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempts = 1;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match attempt().await {
+            Err(e) if attempts < MAX_ATTEMPTS && is_retryable(&e) => {
+                warn!(
+                    "Failed to fetch {}: {}. Retrying in {:.1}s",
+                    url.color(Color::Blue),
+                    e,
+                    backoff.as_secs_f32()
+                );
+                sleep(backoff).await;
+                attempts += 1;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a single GET request for `url`, failing with a [`StatusError`] if it is unsuccessful.
+pub async fn request(url: &str) -> Result<Response> {
+    send(url, 0, None).await
+}
+
+/// Sends a GET request for `url`, retrying transient failures.
+pub async fn get(url: &str) -> Result<Response> {
+    retry(url, || request(url)).await
+}
+
+/// Follows redirects like the default policy, except those leaving a repository that has
+/// credentials, as they would carry its credentials along. These are handed back to [`send`] to
+/// be followed with the credentials of wherever they lead instead.
+///
+/// `credentials_of` returns the name of the repository whose credentials apply to a URL.
+fn redirect_policy<F>(credentials_of: F) -> Policy
+where
+    F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+{
+    Policy::custom(move |attempt| {
+        let leaves_repository = attempt.previous().last().is_some_and(|previous| {
+            credentials_of(previous.as_str())
+                .is_some_and(|repo| credentials_of(attempt.url().as_str()) != Some(repo))
+        });
+
+        if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if leaves_repository {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Sends a GET request for `url` from byte `offset` on, as long as the file still matches
+/// `validator`. A `416 Range Not Satisfiable` response is returned as is so the caller can tell
+/// whether its partial download is complete.
+async fn send(url: &str, offset: u64, validator: Option<&str>) -> Result<Response> {
+    send_with(&CLIENT, url, offset, validator, authorize_for).await
+}
+
+/// Sends a GET request with `client`, adding the credentials `authorize` gives for each URL
+/// along the way. Redirects the client doesn't follow itself are followed here, so every hop
+/// is authorized for its own URL.
+async fn send_with<F>(
+    client: &Client,
+    url: &str,
+    offset: u64,
+    validator: Option<&str>,
+    authorize: F,
+) -> Result<Response>
+where
+    F: Fn(&str, RequestBuilder) -> Result<RequestBuilder>,
+{
+    let mut target = Url::parse(url)?;
+    let mut redirects = 0;
+    let response = loop {
+        let mut request = authorize(target.as_str(), client.get(target.clone()))?;
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+            if let Some(validator) = validator {
+                request = request.header(IF_RANGE, validator);
+            }
+        }
+
+        let response = request.send().await?;
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok());
+        let Some(location) = location.filter(|_| response.status().is_redirection()) else {
+            break response;
+        };
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            anyhow::bail!("Too many redirects fetching {}", url.color(Color::Blue));
+        }
+        target = target.join(location)?;
+    };
+
+    let status = response.status();
+    let is_unsatisfied_range = offset > 0 && status == StatusCode::RANGE_NOT_SATISFIABLE;
+    if !status.is_success() && !is_unsatisfied_range {
+        return Err(StatusError {
+            url: url.to_owned(),
+            status,
+        }
+        .into());
+    }
+
+    Ok(response)
+}
+
+/// Adds the credentials of the repository `url` belongs to, if it has any.
+fn authorize_for(url: &str, request: RequestBuilder) -> Result<RequestBuilder> {
+    let Some(repo) = CONFIG.repository_for_url(url) else {
+        return Ok(request);
+    };
+    let Some(ref auth) = repo.auth else {
+        return Ok(request);
+    };
+
+    authorize(request, auth).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to read credentials of repository {}: {:#}",
+            repo.name.clone().color(Color::Cyan),
+            e
+        )
+    })
+}
+
+fn authorize(request: RequestBuilder, auth: &RepositoryAuth) -> Result<RequestBuilder> {
+    let request = match auth {
+        RepositoryAuth::Bearer { token } => request.bearer_auth(token.read()?),
+        RepositoryAuth::Basic { username, password } => {
+            request.basic_auth(username, Some(password.read()?))
+        }
+        RepositoryAuth::Header { name, value } => {
+            let mut value = HeaderValue::from_str(&value.read()?)?;
+            value.set_sensitive(true);
+            request.header(name.as_str(), value)
+        }
+    };
+
+    Ok(request)
+}
+
+/// Downloads `url` to `path`, returning the size of the file.
+///
+/// A partial download left at `path` is resumed if the server supports range requests and the
+/// file hasn't changed since, and discarded otherwise. Interrupted transfers are retried,
+/// resuming from what was written.
+pub async fn download_file(url: &str, path: &Path, progress: Option<&ProgressBar>) -> Result<u64> {
+    retry(url, || download_to(url, path, progress)).await
+}
+
+async fn download_to(url: &str, path: &Path, progress: Option<&ProgressBar>) -> Result<u64> {
+    let validator_path = validator_path(path);
+    let validator = fs::read_to_string(&validator_path)
+        .await
+        .ok()
+        .filter(|validator| !validator.is_empty());
+    let mut offset = fs::metadata(path).await.map(|meta| meta.len()).unwrap_or(0);
+    if offset > 0 && validator.is_none() {
+        // There is no telling which version of the file the partial download belongs to.
+        fs::remove_file(path).await?;
+        offset = 0;
+    }
+
+    let response = send(url, offset, validator.as_deref()).await?;
+    let (range_start, range_total) = content_range(response.headers());
+
+    let resume = match response.status() {
+        // The server only sends a range if the file still matches the validator.
+        StatusCode::PARTIAL_CONTENT if range_start == Some(offset) => true,
+        StatusCode::RANGE_NOT_SATISFIABLE
+            if range_total == Some(offset) && validator_of(response.headers()) == validator =>
+        {
+            // The previous attempt got everything but couldn't tell it was done.
+            remove_if_exists(&validator_path).await?;
+            if let Some(pb) = progress {
+                pb.set_length(offset);
+                pb.set_position(offset);
+            }
+            return Ok(offset);
+        }
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+            // The partial file doesn't line up with what the server has, start over.
+            fs::remove_file(path).await?;
+            remove_if_exists(&validator_path).await?;
+            return Box::pin(download_to(url, path, progress)).await;
+        }
+        // Either nothing was downloaded yet, the file changed or the server ignored the range.
+        _ => false,
+    };
+
+    if !resume {
+        match validator_of(response.headers()) {
+            Some(validator) => fs::write(&validator_path, validator).await?,
+            None => remove_if_exists(&validator_path).await?,
+        }
+    }
+
+    let mut downloaded = if resume { offset } else { 0 };
+    let total = range_total.or(response.content_length().map(|len| len + downloaded));
+    if let Some(pb) = progress {
+        pb.set_length(total.unwrap_or(downloaded));
+        pb.set_position(downloaded);
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resume)
+        .truncate(!resume)
+        .open(path)
+        .await?;
+
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // Keep what was written so the retry can resume from it.
+                file.flush().await?;
+                return Err(e.into());
+            }
+        };
+        file.write_all(&chunk).await?;
+        downloaded += chunk.len() as u64;
+        if let Some(pb) = progress {
+            pb.set_position(downloaded);
+            if total.is_none() {
+                pb.set_length(downloaded);
+            }
+        }
+    }
+    file.flush().await?;
+    remove_if_exists(&validator_path).await?;
+
+    Ok(downloaded)
+}
+
+/// Path of the file recording which version of the file a partial download at `path` is of.
+fn validator_path(path: &Path) -> PathBuf {
+    let mut validator_path = path.as_os_str().to_owned();
+    validator_path.push(".validator");
+    PathBuf::from(validator_path)
+}
+
+/// Identifies the version of the file a response is for by its strong ETag, or else by its
+/// modification date.
+fn validator_of(headers: &HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+        .map(String::from)
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Parses the `Content-Range` header into the start of the range and the total size, where
+/// known.
+fn content_range(headers: &HeaderMap) -> (Option<u64>, Option<u64>) {
+    let Some(value) = headers
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return (None, None);
+    };
+
+    let value = value.trim().trim_start_matches("bytes").trim();
+    let (range, total) = value.split_once('/').unwrap_or((value, "*"));
+    let start = range
+        .split_once('-')
+        .and_then(|(start, _)| start.trim().parse().ok());
+
+    (start, total.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+
+    /// Answers a single request with `response`, returning the lowercased request head.
+    fn serve_once(listener: TcpListener, response: String) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            head.to_lowercase()
+        })
+    }
+
+    #[tokio::test]
+    async fn redirect_to_another_host_drops_credentials() {
+        let mirror = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mirror_url = format!("http://127.0.0.1:{}/", mirror.local_addr().unwrap().port());
+        let other_url = format!(
+            "http://localhost:{}/file",
+            other.local_addr().unwrap().port()
+        );
+        let mirror_head = serve_once(
+            mirror,
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                other_url
+            ),
+        );
+        let other_head = serve_once(
+            other,
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_owned(),
+        );
+
+        let repo_url = mirror_url.clone();
+        let client = Client::builder()
+            .redirect(redirect_policy(move |url| {
+                url.starts_with(&repo_url).then(|| "private".to_owned())
+            }))
+            .build()
+            .unwrap();
+        let response = send_with(
+            &client,
+            &format!("{}file", mirror_url),
+            0,
+            None,
+            |url, request| {
+                if url.starts_with(&mirror_url) {
+                    Ok(request.bearer_auth("secret").header("x-token", "secret"))
+                } else {
+                    Ok(request)
+                }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "ok");
+        let mirror_head = mirror_head.join().unwrap();
+        assert!(mirror_head.contains("authorization: bearer secret"));
+        assert!(mirror_head.contains("x-token: secret"));
+        let other_head = other_head.join().unwrap();
+        assert!(!other_head.contains("authorization"));
+        assert!(!other_head.contains("x-token"));
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|&(name, value)| {
+                (
+                    reqwest::header::HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn content_range_parses_start_and_total() {
+        assert_eq!(
+            content_range(&headers(&[("content-range", "bytes 100-199/200")])),
+            (Some(100), Some(200))
+        );
+        assert_eq!(
+            content_range(&headers(&[("content-range", "bytes 100-199/*")])),
+            (Some(100), None)
+        );
+        assert_eq!(
+            content_range(&headers(&[("content-range", "bytes */200")])),
+            (None, Some(200))
+        );
+        assert_eq!(content_range(&headers(&[])), (None, None));
+    }
+
+    #[test]
+    fn validator_of_prefers_strong_etag() {
+        assert_eq!(
+            validator_of(&headers(&[
+                ("etag", "\"abc\""),
+                ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ])),
+            Some("\"abc\"".to_owned())
+        );
+        assert_eq!(
+            validator_of(&headers(&[
+                ("etag", "W/\"abc\""),
+                ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ])),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned())
+        );
+        assert_eq!(validator_of(&headers(&[("etag", "W/\"abc\"")])), None);
+    }
+
+    fn status_error(status: StatusCode) -> anyhow::Error {
+        StatusError {
+            url: "https://example.com".to_owned(),
+            status,
+        }
+        .into()
+    }
+
+    #[test]
+    fn is_retryable_accepts_transient_statuses() {
+        assert!(is_retryable(&status_error(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_retryable(&status_error(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(is_retryable(
+            &status_error(StatusCode::BAD_GATEWAY).context("Failed to download")
+        ));
+        assert!(!is_retryable(&status_error(StatusCode::NOT_FOUND)));
+        assert!(!is_retryable(&status_error(StatusCode::UNAUTHORIZED)));
+        assert!(!is_retryable(&anyhow::Error::from(std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied
+        ))));
+    }
+
+    #[tokio::test]
+    async fn is_retryable_accepts_connection_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        drop(listener);
+
+        let error = Client::new().get(url).send().await.unwrap_err();
+        assert!(is_retryable(&error.into()));
+    }
+}
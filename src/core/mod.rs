@@ -2,5 +2,6 @@ pub mod color;
 pub mod config;
 pub mod constant;
 pub mod file;
+pub mod http;
 pub mod log;
 pub mod util;
@@ -4,12 +4,14 @@ use std::{
     io::Write,
     mem,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
 use futures::StreamExt;
 use indicatif::{ProgressState, ProgressStyle};
 use libc::{geteuid, getpwuid, ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
+use reqwest::Url;
 use termion::cursor;
 use tokio::{
     fs::{self, File},
@@ -21,7 +23,8 @@ use crate::core::constant::ROOT_PATH;
 
 use super::{
     color::{Color, ColorExt},
-    constant::{BIN_PATH, CACHE_PATH, INSTALL_TRACK_PATH, PACKAGES_PATH, REGISTRY_PATH},
+    constant::{BIN_PATH, CACHE_PATH, DB_PATH, PACKAGES_PATH, REGISTRY_PATH},
+    http::{self, StatusError},
 };
 
 fn get_username() -> Result<String> {
@@ -122,8 +125,9 @@ pub fn parse_size(size_str: &str) -> Option<u64> {
 
     for (unit, multiplier) in &units {
         let size_str = size_str.to_uppercase();
-        if size_str.ends_with(unit) {
-            let number_part = size_str.trim_end_matches(unit).trim();
+        let unit = unit.to_uppercase();
+        if size_str.ends_with(&unit) {
+            let number_part = size_str.trim_end_matches(&unit).trim();
             if let Ok(num) = number_part.parse::<f64>() {
                 return Some((num * (*multiplier as f64)) as u64);
             }
@@ -133,6 +137,31 @@ pub fn parse_size(size_str: &str) -> Option<u64> {
     None
 }
 
+/// Parses a duration such as `30m`, `3h` or `1d`. Numbers without a unit are seconds.
+pub fn parse_duration(duration_str: &str) -> Option<Duration> {
+    let duration_str = duration_str.trim();
+    let units = [
+        ("s", 1u64),
+        ("m", 60u64),
+        ("h", 60 * 60),
+        ("d", 24 * 60 * 60),
+    ];
+
+    if let Ok(secs) = duration_str.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    for (unit, multiplier) in &units {
+        if let Some(number_part) = duration_str.strip_suffix(unit) {
+            if let Ok(num) = number_part.trim().parse::<u64>() {
+                return Some(Duration::from_secs(num * multiplier));
+            }
+        }
+    }
+
+    None
+}
+
 pub async fn calculate_checksum(file_path: &Path) -> Result<String> {
     let mut file = File::open(&file_path).await?;
 
@@ -151,15 +180,6 @@ pub async fn calculate_checksum(file_path: &Path) -> Result<String> {
     Ok(hasher.finalize().to_hex().to_string())
 }
 
-pub async fn validate_checksum(checksum: &str, file_path: &Path) -> Result<()> {
-    let final_checksum = calculate_checksum(file_path).await?;
-    if final_checksum == *checksum {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("Checksum verification failed."))
-    }
-}
-
 pub async fn setup_required_paths() -> Result<()> {
     if !BIN_PATH.exists() {
         fs::create_dir_all(&*BIN_PATH).await.with_context(|| {
@@ -179,15 +199,13 @@ pub async fn setup_required_paths() -> Result<()> {
         })?;
     }
 
-    if !INSTALL_TRACK_PATH.exists() {
-        fs::create_dir_all(&*INSTALL_TRACK_PATH)
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to create installs directory: {}",
-                    INSTALL_TRACK_PATH.to_string_lossy().color(Color::Blue)
-                )
-            })?;
+    if !DB_PATH.exists() {
+        fs::create_dir_all(&*DB_PATH).await.with_context(|| {
+            format!(
+                "Failed to create database directory: {}",
+                DB_PATH.to_string_lossy().color(Color::Blue)
+            )
+        })?;
     }
 
     if !PACKAGES_PATH.exists() {
@@ -202,38 +220,56 @@ pub async fn setup_required_paths() -> Result<()> {
     Ok(())
 }
 
-pub async fn download(url: &str, what: &str, silent: bool) -> Result<Vec<u8>> {
-    let client = reqwest::Client::new();
-    let response = client.get(url).send().await?;
+/// Returns the path a `file://` URL points to.
+pub fn file_url_path(url: &str) -> Option<PathBuf> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+}
 
-    if !response.status().is_success() {
-        return Err(anyhow::anyhow!(
-            "Error fetching {} from {} [{}]",
-            what.color(Color::Cyan),
-            url.color(Color::Blue),
-            response.status().color(Color::Red)
-        ));
+pub async fn download(url: &str, what: &str, silent: bool) -> Result<Vec<u8>> {
+    if let Some(path) = file_url_path(url) {
+        return fs::read(&path).await.with_context(|| {
+            format!(
+                "Error reading {} from {}",
+                what.color(Color::Cyan),
+                path.to_string_lossy().color(Color::Blue)
+            )
+        });
     }
 
-    let mut content = Vec::new();
+    http::retry(url, || async {
+        let response = http::request(url).await?;
 
-    if !silent {
-        info!(
-            "Fetching {} from {} [{}]",
-            what.color(Color::Cyan),
-            url.color(Color::Blue),
-            format_bytes(response.content_length().unwrap_or_default())
-        );
-    }
+        if !silent {
+            info!(
+                "Fetching {} from {} [{}]",
+                what.color(Color::Cyan),
+                url.color(Color::Blue),
+                format_bytes(response.content_length().unwrap_or_default())
+            );
+        }
 
-    let mut stream = response.bytes_stream();
+        let mut content = Vec::new();
+        let mut stream = response.bytes_stream();
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.context("Failed to read chunk")?;
-        content.extend_from_slice(&chunk);
-    }
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
 
-    Ok(content)
+        Ok(content)
+    })
+    .await
+    .map_err(|e| match e.downcast_ref::<StatusError>() {
+        Some(err) => anyhow::anyhow!(
+            "Error fetching {} from {} [{}]",
+            what.color(Color::Cyan),
+            url.color(Color::Blue),
+            err.status.color(Color::Red)
+        ),
+        None => e,
+    })
 }
 
 pub async fn cleanup() -> Result<()> {
@@ -295,7 +331,9 @@ pub fn wrap_text(text: &str, available_width: usize, indent: u16) -> String {
             // Regular character
             if current_line_length >= available_width {
                 wrapped_text.push('\n');
-                wrapped_text.push_str(&cursor::Right(indent).to_string());
+                if indent > 0 {
+                    wrapped_text.push_str(&cursor::Right(indent).to_string());
+                }
                 current_line_length = 0;
             }
             wrapped_text.push(c);
@@ -401,22 +439,50 @@ pub fn interactive_ask(ques: &str, ask_type: AskType) -> Result<String> {
 }
 
 pub fn print_env() {
-    let root_path = ROOT_PATH
-        .is_symlink()
-        .then(|| ROOT_PATH.read_link().unwrap())
-        .unwrap_or(ROOT_PATH.to_path_buf());
+    let root_path = if ROOT_PATH.is_symlink() {
+        ROOT_PATH.read_link().unwrap()
+    } else {
+        ROOT_PATH.to_path_buf()
+    };
 
-    let bin_path = BIN_PATH
-        .is_symlink()
-        .then(|| BIN_PATH.read_link().unwrap())
-        .unwrap_or(BIN_PATH.to_path_buf());
+    let bin_path = if BIN_PATH.is_symlink() {
+        BIN_PATH.read_link().unwrap()
+    } else {
+        BIN_PATH.to_path_buf()
+    };
 
-    let cache_path = CACHE_PATH
-        .is_symlink()
-        .then(|| CACHE_PATH.read_link().unwrap())
-        .unwrap_or(CACHE_PATH.to_path_buf());
+    let cache_path = if CACHE_PATH.is_symlink() {
+        CACHE_PATH.read_link().unwrap()
+    } else {
+        CACHE_PATH.to_path_buf()
+    };
 
     info!("SOAR_ROOT={}", root_path.display());
     info!("SOAR_BIN={}", bin_path.display());
     info!("SOAR_CACHE={}", cache_path.display());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_reads_formatted_sizes_back() {
+        for bytes in [0, 512, 1536, 10 * 1024 * 1024, 3 * 1024 * 1024 * 1024] {
+            assert_eq!(parse_size(&format_bytes(bytes)), Some(bytes));
+        }
+
+        // Formatting rounds to two decimals.
+        let bytes = 1_234_567;
+        let parsed = parse_size(&format_bytes(bytes)).unwrap();
+        assert!(parsed.abs_diff(bytes) < 1024 * 1024 / 100);
+    }
+
+    #[test]
+    fn parse_size_ignores_unit_case() {
+        assert_eq!(parse_size("10 kib"), Some(10 * 1024));
+        assert_eq!(parse_size("2MB"), Some(2_000_000));
+        assert_eq!(parse_size("12 b"), Some(12));
+        assert_eq!(parse_size("12 parsecs"), None);
+    }
+}
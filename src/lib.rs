@@ -1,23 +1,35 @@
 use anyhow::Result;
 use clap::Parser;
-use cli::{Args, Commands, SelfAction};
+use cli::{Args, Commands, ProfileAction, RepoAction, SandboxAction, SelfAction};
 use misc::{
     download::{download, download_and_save, github::fetch_github_releases, ApiType},
     health::check_health,
 };
-use package::build;
-use registry::PackageRegistry;
+use package::{
+    build,
+    sandbox::{self, SandboxRules},
+};
+use registry::{
+    index::build_index,
+    installed::{refresh_sandbox_wrappers, InstalledPackages},
+    print_repository_status, validate_repository, PackageRegistry,
+};
 use tokio::fs;
 use tracing::{debug, error, info, trace, warn};
 
 use core::{
     color::{Color, ColorExt},
-    config::{self, generate_default_config},
+    config::{
+        self, add_profile, add_repository, generate_default_config, list_profiles,
+        list_repositories, remove_repository, set_default_profile, set_repository_disabled,
+        set_repository_priority, Profile, Repository, CONFIG,
+    },
     constant::BIN_PATH,
     log::setup_logging,
-    util::{cleanup, print_env, setup_required_paths},
+    util::{build_path, cleanup, print_env, setup_required_paths},
 };
 use std::{
+    collections::HashMap,
     env::{self, consts::ARCH},
     io::Read,
     path::Path,
@@ -31,7 +43,7 @@ mod registry;
 
 async fn handle_cli() -> Result<()> {
     let mut args = env::args().collect::<Vec<_>>();
-    let self_bin = args.get(0).unwrap().clone();
+    let self_bin = args.first().unwrap().clone();
     let self_version = env!("CARGO_PKG_VERSION");
 
     let mut i = 0;
@@ -55,8 +67,27 @@ async fn handle_cli() -> Result<()> {
 
     setup_logging(&args);
 
+    // Sandbox wrappers of installed binaries end up here, skip the setup and checks.
+    if let Commands::Sandbox {
+        action:
+            SandboxAction::Exec {
+                read,
+                write,
+                no_net,
+                command,
+            },
+    } = args.command
+    {
+        let rules = SandboxRules {
+            fs_read: read,
+            fs_write: write,
+            net: !no_net,
+        };
+        return sandbox::exec(&command, &rules);
+    }
+
     debug!("Initializing soar");
-    config::init();
+    config::init(args.profile.clone(), args.offline);
 
     debug!("Setting up paths");
     setup_required_paths().await?;
@@ -76,6 +107,11 @@ async fn handle_cli() -> Result<()> {
     trace!("Running cleanup");
     let _ = cleanup().await;
 
+    trace!("Refreshing sandbox wrappers");
+    if let Err(err) = refresh_sandbox_wrappers().await {
+        warn!("Failed to refresh sandbox wrappers: {:#}", err);
+    }
+
     match args.command {
         Commands::Install {
             packages,
@@ -84,6 +120,7 @@ async fn handle_cli() -> Result<()> {
             portable_home,
             portable_config,
             yes,
+            allow_checksum_mismatch,
         } => {
             if portable.is_some() && (portable_home.is_some() || portable_config.is_some()) {
                 error!("--portable cannot be used with --portable-home or --portable-config");
@@ -104,19 +141,51 @@ async fn handle_cli() -> Result<()> {
                     portable_config,
                     yes,
                     args.quiet,
+                    allow_checksum_mismatch,
                 )
                 .await?;
         }
         Commands::Sync => {
-            registry.await?;
+            CONFIG.ensure_online("sync repositories")?;
+            PackageRegistry::sync().await?;
+            print_repository_status().await;
         }
         Commands::Remove { packages, exact } => {
-            registry.await?.remove_packages(&packages, exact).await?;
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages.remove_packages(&packages, exact).await?;
         }
-        Commands::Update { packages } => {
+        Commands::Update {
+            packages,
+            allow_checksum_mismatch,
+        } => {
+            CONFIG.ensure_online("update packages")?;
             registry
                 .await?
-                .update(packages.as_deref(), args.quiet)
+                .update(packages.as_deref(), args.quiet, allow_checksum_mismatch)
+                .await?;
+        }
+        Commands::Pin { packages } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages.pin_packages(&packages, true).await?;
+        }
+        Commands::Unpin { packages } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages.pin_packages(&packages, false).await?;
+        }
+        Commands::Rollback { package, to } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages
+                .rollback_package(&package, to.as_deref())
+                .await?;
+        }
+        Commands::Disable { packages } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages.disable_packages(&packages, true).await?;
+        }
+        Commands::Enable { packages } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            installed_packages
+                .disable_packages(&packages, false)
                 .await?;
         }
         Commands::ListInstalledPackages { packages } => {
@@ -139,13 +208,22 @@ async fn handle_cli() -> Result<()> {
             registry.await?.list(collection.as_deref()).await?;
         }
         Commands::Inspect { package } => {
+            CONFIG.ensure_online("fetch build scripts")?;
             registry.await?.inspect(&package, "script").await?;
         }
         Commands::Log { package } => {
+            CONFIG.ensure_online("fetch build logs")?;
             registry.await?.inspect(&package, "log").await?;
         }
-        Commands::Run { command, yes } => {
-            registry.await?.run(command.as_ref(), yes).await?;
+        Commands::Run {
+            command,
+            yes,
+            allow_checksum_mismatch,
+        } => {
+            registry
+                .await?
+                .run(command.as_ref(), yes, allow_checksum_mismatch)
+                .await?;
         }
         Commands::Use { package } => {
             registry.await?.use_package(&package, args.quiet).await?;
@@ -158,6 +236,7 @@ async fn handle_cli() -> Result<()> {
             match_keywords,
             exclude_keywords,
         } => {
+            CONFIG.ensure_online("download files")?;
             download_and_save(
                 registry.await?,
                 links.as_ref(),
@@ -178,7 +257,111 @@ async fn handle_cli() -> Result<()> {
         Commands::Env => {
             print_env();
         }
+        Commands::Sandbox { action } => {
+            let mut installed_packages = InstalledPackages::new().await?;
+            match action {
+                SandboxAction::Set {
+                    packages,
+                    read,
+                    write,
+                    no_net,
+                } => {
+                    let rules = SandboxRules {
+                        fs_read: read,
+                        fs_write: write,
+                        net: !no_net,
+                    };
+                    installed_packages
+                        .set_sandbox_rules(&packages, Some(rules))
+                        .await?;
+                }
+                SandboxAction::Remove { packages } => {
+                    installed_packages
+                        .set_sandbox_rules(&packages, None)
+                        .await?;
+                }
+                SandboxAction::List => installed_packages.list_sandbox_rules()?,
+                SandboxAction::Exec { .. } => unreachable!(),
+            }
+        }
+        Commands::Profile { action } => match action {
+            ProfileAction::List => list_profiles()?,
+            ProfileAction::Add {
+                name,
+                root,
+                bin,
+                cache,
+                packages,
+                default,
+            } => {
+                let profile = Profile {
+                    soar_root: root,
+                    soar_cache: cache,
+                    soar_bin: bin,
+                    soar_packages: packages,
+                    soar_db: None,
+                    sandbox: None,
+                };
+                add_profile(&name, profile, default)?;
+            }
+            ProfileAction::Default { name } => set_default_profile(&name)?,
+        },
+        Commands::Repo { action } => match action {
+            RepoAction::List => list_repositories()?,
+            RepoAction::Add {
+                name,
+                url,
+                mirror,
+                metadata,
+                source,
+            } => {
+                // Local repositories are stored by absolute path so they work from any directory.
+                let url = std::iter::once(url)
+                    .chain(mirror)
+                    .map(|url| {
+                        std::fs::canonicalize(&url)
+                            .map(|path| path.to_string_lossy().into_owned())
+                            .unwrap_or_else(|_| url.trim_end_matches('/').to_owned())
+                    })
+                    .collect::<Vec<_>>()
+                    .into();
+
+                let mut sources = HashMap::<String, Vec<String>>::new();
+                for (collection, url) in source {
+                    sources.entry(collection).or_default().push(url);
+                }
+                let sources = sources
+                    .into_iter()
+                    .map(|(collection, urls)| (collection, urls.into()))
+                    .collect();
+
+                let repo = Repository {
+                    name,
+                    url,
+                    metadata,
+                    sources,
+                    sandbox: None,
+                    pubkey: None,
+                    sync_interval: None,
+                    disabled: None,
+                    priority: None,
+                    auth: None,
+                };
+                validate_repository(&repo).await?;
+                add_repository(repo)?;
+            }
+            RepoAction::Remove { name } => remove_repository(&name)?,
+            RepoAction::SetPriority { name, priority } => set_repository_priority(&name, priority)?,
+            RepoAction::Enable { name } => set_repository_disabled(&name, false)?,
+            RepoAction::Disable { name } => set_repository_disabled(&name, true)?,
+            RepoAction::BuildIndex {
+                dir,
+                output,
+                collection,
+            } => build_index(&build_path(&dir)?, &output, &collection).await?,
+        },
         Commands::Build { files } => {
+            CONFIG.ensure_online("build packages")?;
             for file in files {
                 build::init(&file).await?;
             }
@@ -186,13 +369,14 @@ async fn handle_cli() -> Result<()> {
         Commands::SelfCmd { action } => {
             match action {
                 SelfAction::Update => {
+                    CONFIG.ensure_online("update soar")?;
                     let is_nightly = self_version.starts_with("nightly");
                     let gh_releases =
                         fetch_github_releases(&ApiType::PkgForge, "pkgforge/soar").await?;
 
                     let release = gh_releases.iter().find(|rel| {
                         if is_nightly {
-                            return rel.name.starts_with("nightly") && rel.name != self_version;
+                            rel.name.starts_with("nightly") && rel.name != self_version
                         } else {
                             rel.tag_name
                                 .trim_start_matches('v')
@@ -236,7 +420,16 @@ async fn handle_cli() -> Result<()> {
 }
 
 pub async fn init() {
-    if let Err(e) = handle_cli().await {
-        error!("{}", e);
+    // Interrupted installs are rolled back when their tasks are dropped, so stop here instead
+    // of letting the signal kill the process.
+    tokio::select! {
+        result = handle_cli() => {
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Interrupted");
+        }
     }
 }
@@ -3,7 +3,7 @@ use std::env;
 use anyhow::{Context, Result};
 use regex::Regex;
 use reqwest::{
-    header::{HeaderMap, AUTHORIZATION, USER_AGENT},
+    header::{HeaderMap, AUTHORIZATION},
     Response,
 };
 use serde::{Deserialize, Serialize};
@@ -12,6 +12,7 @@ use tracing::{debug, error, info, trace};
 use crate::{
     core::{
         color::{Color, ColorExt},
+        http::CLIENT,
         util::{format_bytes, interactive_ask, AskType},
     },
     misc::download::download,
@@ -40,7 +41,6 @@ pub static GITHUB_URL_REGEX: &str =
     r"^(?i)(?:https?://)?(?:github(?:\.com)?[:/])([^/@]+/[^/@]+)(?:@([^/\s]*)?)?$";
 
 async fn call_github_api(gh_api: &ApiType, user_repo: &str) -> Result<Response> {
-    let client = reqwest::Client::new();
     let url = format!(
         "{}/repos/{}/releases?per_page=100",
         match gh_api {
@@ -50,14 +50,13 @@ async fn call_github_api(gh_api: &ApiType, user_repo: &str) -> Result<Response>
         user_repo
     );
     let mut headers = HeaderMap::new();
-    headers.insert(USER_AGENT, "pkgforge/soar".parse()?);
     if matches!(gh_api, ApiType::Primary) {
         if let Ok(token) = env::var("GITHUB_TOKEN") {
             trace!("Using Github token: {}", token);
             headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
         }
     }
-    client
+    CLIENT
         .get(&url)
         .headers(headers)
         .send()
@@ -127,7 +126,7 @@ pub async fn handle_github_download(
     output: Option<String>,
     match_keywords: Option<&[String]>,
     exclude_keywords: Option<&[String]>,
-    asset_regexes: &Vec<Regex>,
+    asset_regexes: &[Regex],
     yes: bool,
 ) -> Result<()> {
     if let Some(caps) = re.captures(link) {
@@ -174,7 +173,7 @@ pub async fn handle_github_download(
                     asset_regexes
                         .iter()
                         .all(|regex| regex.is_match(&asset.name))
-                        && match_keywords.map_or(true, |keywords| {
+                        && match_keywords.is_none_or(|keywords| {
                             keywords.iter().all(|keyword| {
                                 keyword
                                     .split(',')
@@ -185,7 +184,7 @@ pub async fn handle_github_download(
                                     })
                             })
                         })
-                        && exclude_keywords.map_or(true, |keywords| {
+                        && exclude_keywords.is_none_or(|keywords| {
                             keywords.iter().all(|keyword| {
                                 keyword
                                     .split(',')
@@ -3,7 +3,7 @@ use std::env;
 use anyhow::{Context, Result};
 use regex::Regex;
 use reqwest::{
-    header::{HeaderMap, AUTHORIZATION, USER_AGENT},
+    header::{HeaderMap, AUTHORIZATION},
     Response,
 };
 use serde::{Deserialize, Serialize};
@@ -12,6 +12,7 @@ use tracing::{debug, error, info, trace};
 use crate::{
     core::{
         color::{Color, ColorExt},
+        http::CLIENT,
         util::{interactive_ask, AskType},
     },
     misc::download::download,
@@ -42,7 +43,6 @@ pub static GITLAB_URL_REGEX: &str =
     r"^(?i)(?:https?://)?(?:gitlab(?:\.com)?[:/])([^/@]+/[^/@]+)(?:@([^/\s]*)?)?$";
 
 async fn call_gitlab_api(gh_api: &ApiType, user_repo: &str) -> Result<Response> {
-    let client = reqwest::Client::new();
     let url = format!(
         "{}/api/v4/projects/{}/releases",
         match gh_api {
@@ -52,14 +52,13 @@ async fn call_gitlab_api(gh_api: &ApiType, user_repo: &str) -> Result<Response>
         user_repo.replace("/", "%2F")
     );
     let mut headers = HeaderMap::new();
-    headers.insert(USER_AGENT, "pkgforge/soar".parse()?);
     if matches!(gh_api, ApiType::Primary) {
         if let Ok(token) = env::var("GITLAB_TOKEN") {
             trace!("Using Gitlab token: {}", token);
             headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
         }
     }
-    client
+    CLIENT
         .get(&url)
         .headers(headers)
         .send()
@@ -121,7 +120,7 @@ pub async fn handle_gitlab_download(
     output: Option<String>,
     match_keywords: Option<&[String]>,
     exclude_keywords: Option<&[String]>,
-    asset_regexes: &Vec<Regex>,
+    asset_regexes: &[Regex],
     yes: bool,
 ) -> Result<()> {
     if let Some(caps) = re.captures(link) {
@@ -166,7 +165,7 @@ pub async fn handle_gitlab_download(
                     asset_regexes
                         .iter()
                         .all(|regex| regex.is_match(&asset.name))
-                        && match_keywords.map_or(true, |keywords| {
+                        && match_keywords.is_none_or(|keywords| {
                             keywords.iter().all(|keyword| {
                                 keyword
                                     .split(',')
@@ -177,7 +176,7 @@ pub async fn handle_gitlab_download(
                                     })
                             })
                         })
-                        && exclude_keywords.map_or(true, |keywords| {
+                        && exclude_keywords.is_none_or(|keywords| {
                             keywords.iter().all(|keyword| {
                                 keyword
                                     .split(',')
@@ -2,15 +2,14 @@ use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path};
 
 use anyhow::{Context, Result};
 use chrono::Utc;
-use futures::StreamExt;
 use github::{handle_github_download, GITHUB_URL_REGEX};
 use gitlab::{handle_gitlab_download, GITLAB_URL_REGEX};
 use indicatif::ProgressBar;
 use regex::Regex;
-use reqwest::{header::USER_AGENT, StatusCode, Url};
+use reqwest::{StatusCode, Url};
 use tokio::{
     fs::{self, File},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncReadExt, BufReader},
 };
 use tracing::{error, info};
 
@@ -21,7 +20,8 @@ use crate::{
     core::{
         color::{Color, ColorExt},
         constant::ELF_MAGIC_BYTES,
-        util::{download_progress_style, format_bytes},
+        http::download_file,
+        util::download_progress_style,
     },
     package::parse_package_query,
     registry::{select_single_package, PackageRegistry},
@@ -56,21 +56,6 @@ async fn is_elf(file_path: &Path) -> bool {
 }
 
 pub async fn download(url: &str, output: Option<String>) -> Result<()> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .header(USER_AGENT, "pkgforge/soar")
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        return Err(anyhow::anyhow!(
-            "Error fetching {} [{}]",
-            url.color(Color::Blue),
-            response.status().color(Color::Red)
-        ));
-    }
-
     let filename = output.unwrap_or(extract_filename(url));
     let filename = if filename.ends_with("/") {
         format!(
@@ -94,35 +79,12 @@ pub async fn download(url: &str, output: Option<String>) -> Result<()> {
 
     let temp_path = format!("{}.tmp", output_path.display());
 
-    info!(
-        "Downloading file from {} [{}]",
-        url.color(Color::Blue),
-        format_bytes(response.content_length().unwrap_or_default()).color(Color::Yellow)
-    );
+    info!("Downloading file from {}", url.color(Color::Blue));
 
-    let content_length = response.content_length().unwrap_or(0);
-    let progress_bar = ProgressBar::new(content_length);
+    let progress_bar = ProgressBar::new(0);
     progress_bar.set_style(download_progress_style(false));
 
-    let mut stream = response.bytes_stream();
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(&temp_path)
-        .await
-        .context("Failed to open temp file for writing")?;
-
-    let mut downloaded_bytes = 0u64;
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.context("Failed to read chunk")?;
-        file.write_all(&chunk).await?;
-        downloaded_bytes = downloaded_bytes.saturating_add(chunk.len() as u64);
-        progress_bar.set_position(downloaded_bytes);
-        if content_length == 0 {
-            progress_bar.set_length(downloaded_bytes);
-        }
-    }
+    download_file(url, Path::new(&temp_path), Some(&progress_bar)).await?;
     progress_bar.finish();
 
     fs::rename(&temp_path, &output_path).await?;
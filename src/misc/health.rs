@@ -1,13 +1,17 @@
 use std::{cmp::Ordering, future::Future, os::unix::fs::PermissionsExt, path::Path, pin::Pin};
 
 use futures::future::join_all;
+use landlock::{Access, AccessFs, CompatLevel, Compatible, Ruleset, RulesetAttr, ABI};
 use libc::{fork, unshare, waitpid, CLONE_NEWUSER, PR_CAPBSET_READ};
 use tokio::fs;
 use tracing::{info, warn};
 
-use crate::core::{
-    color::{Color, ColorExt},
-    constant::{CAP_MKNOD, CAP_SYS_ADMIN},
+use crate::{
+    core::{
+        color::{Color, ColorExt},
+        constant::{CAP_MKNOD, CAP_SYS_ADMIN},
+    },
+    registry::print_repository_status,
 };
 
 fn check_capability(cap: i32) -> bool {
@@ -56,6 +60,12 @@ pub async fn check_health() {
         }
     });
 
+    info!("\n{0}  LANDLOCK CHECK {0}", "☵".repeat(4));
+    check_landlock();
+
+    info!("\n{0}  REPOSITORY CHECK {0}", "☵".repeat(4));
+    print_repository_status().await;
+
     info!("\n{0}  USER NAMESPACE CHECK {0}", "☵".repeat(4));
     for error in &errors {
         warn!("{}", error);
@@ -126,6 +136,23 @@ async fn check_capabilities() -> Option<&'static str> {
     None
 }
 
+fn check_landlock() {
+    let result = Ruleset::default()
+        .set_compatibility(CompatLevel::HardRequirement)
+        .handle_access(AccessFs::from_all(ABI::V1))
+        .and_then(|ruleset| ruleset.create());
+
+    if result.is_ok() {
+        info!("Landlock checked successfully.");
+    } else {
+        warn!(
+            "Landlock is not supported or not enabled. Sandboxed packages will fail to run.\n{} {}",
+            "More info at:".color(Color::Cyan),
+            "https://docs.kernel.org/userspace-api/landlock.html".color(Color::Blue)
+        );
+    }
+}
+
 async fn check_fusermount() {
     let mut error = String::new();
 
@@ -11,10 +11,14 @@ use image::{imageops::FilterType, DynamicImage, GenericImageView};
 use tokio::{fs, try_join};
 use tracing::{error, info};
 
-use crate::core::{
-    color::{Color, ColorExt},
-    constant::{BIN_PATH, PACKAGES_PATH},
-    util::{download, home_data_path},
+use crate::{
+    core::{
+        color::{Color, ColorExt},
+        constant::{BIN_PATH, PACKAGES_PATH},
+        file::{get_file_type, FileType},
+        util::{download, home_data_path},
+    },
+    registry::installed::PortableOptions,
 };
 
 use super::Package;
@@ -106,20 +110,21 @@ async fn remove_link(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub async fn remove_applinks(name: &str, bin_name: &str, file_path: &Path) -> Result<()> {
+/// Returns the desktop entry and icon of an installed package, paired with the paths they are
+/// linked to in the data directory.
+pub fn applink_paths(name: &str, bin_name: &str, file_path: &Path) -> Vec<(PathBuf, PathBuf)> {
     let home_data = home_data_path();
     let data_path = Path::new(&home_data);
 
-    let desktop_path = data_path
-        .join("applications")
-        .join(format!("{name}-soar.desktop"));
-
-    remove_link(&desktop_path).await?;
+    let mut paths = vec![(
+        file_path.with_extension("desktop"),
+        data_path
+            .join("applications")
+            .join(format!("{name}-soar.desktop")),
+    )];
 
     let original_icon_path = file_path.with_extension("png");
-    if original_icon_path.exists() {
-        let (w, h) = image::image_dimensions(&original_icon_path)?;
-
+    if let Ok((w, h)) = image::image_dimensions(&original_icon_path) {
         let icon_path = data_path
             .join("icons")
             .join("hicolor")
@@ -127,8 +132,26 @@ pub async fn remove_applinks(name: &str, bin_name: &str, file_path: &Path) -> Re
             .join("apps")
             .join(bin_name)
             .with_extension("png");
+        paths.push((original_icon_path, icon_path));
+    }
+
+    paths
+}
+
+pub async fn remove_applinks(name: &str, bin_name: &str, file_path: &Path) -> Result<()> {
+    for (_, link_path) in applink_paths(name, bin_name, file_path) {
+        remove_link(&link_path).await?;
+    }
 
-        remove_link(&icon_path).await?;
+    Ok(())
+}
+
+/// Links the desktop entry and icon extracted at install time back into the data directory.
+pub async fn restore_applinks(name: &str, bin_name: &str, file_path: &Path) -> Result<()> {
+    for (original_path, link_path) in applink_paths(name, bin_name, file_path) {
+        if original_path.exists() {
+            create_symlink(&original_path, &link_path).await?;
+        }
     }
 
     Ok(())
@@ -206,12 +229,10 @@ fn extract_file(
     file: &SquashfsFileReader,
     output_path: &Path,
 ) -> Result<()> {
-    let mut reader = squashfs.file(&file.basic).reader().bytes();
+    let mut reader = squashfs.file(&file.basic).reader();
     let output_file = File::create(output_path)?;
     let mut buf_writer = BufWriter::new(output_file);
-    while let Some(Ok(byte)) = reader.next() {
-        buf_writer.write_all(&[byte])?;
-    }
+    std::io::copy(&mut reader, &mut buf_writer)?;
     Ok(())
 }
 
@@ -302,10 +323,7 @@ pub async fn integrate_using_remote_files(package: &Package, file_path: &Path) -
     fs::write(&icon_output_path, &icon_content).await?;
 
     let desktop_content = if let Some(desktop_url) = desktop_url {
-        match download(desktop_url, "desktop file", false).await {
-            Ok(content) => Some(content),
-            Err(_) => None,
-        }
+        download(desktop_url, "desktop file", false).await.ok()
     } else {
         None
     };
@@ -366,7 +384,9 @@ pub async fn setup_portable_dir(
 
     if let Some(portable_home) = portable_home {
         if portable_home.is_empty() {
-            fs::create_dir(&pkg_home).await?;
+            if !pkg_home.exists() {
+                fs::create_dir(&pkg_home).await?;
+            }
         } else {
             let portable_home = PathBuf::from(portable_home)
                 .join(bin_name)
@@ -377,12 +397,16 @@ pub async fn setup_portable_dir(
                     "Failed to create or access directory at {}",
                     &portable_home.to_string_lossy().color(Color::Blue)
                 ))?;
-            create_symlink(&portable_home, &pkg_home).await?;
+            if !pkg_home.is_symlink() {
+                create_symlink(&portable_home, &pkg_home).await?;
+            }
         }
     }
     if let Some(portable_config) = portable_config {
         if portable_config.is_empty() {
-            fs::create_dir(&pkg_config).await?;
+            if !pkg_config.exists() {
+                fs::create_dir(&pkg_config).await?;
+            }
         } else {
             let portable_config = PathBuf::from(portable_config)
                 .join(bin_name)
@@ -393,9 +417,90 @@ pub async fn setup_portable_dir(
                     "Failed to create or access directory at {}",
                     &portable_config.to_string_lossy().color(Color::Blue)
                 ))?;
-            create_symlink(&portable_config, &pkg_config).await?;
+            if !pkg_config.is_symlink() {
+                create_symlink(&portable_config, &pkg_config).await?;
+            }
         }
     }
 
     Ok(())
 }
+
+/// Base path the portable directories of a FlatImage are created from.
+pub fn flatimage_portable_path(install_path: &Path, bin_name: &str) -> PathBuf {
+    install_path
+        .parent()
+        .unwrap()
+        .join(format!(".{}", bin_name))
+}
+
+/// Sets up the recorded portable directories of an installed package again.
+pub async fn reapply_portable_dir(
+    bin_name: &str,
+    install_path: &Path,
+    options: PortableOptions,
+) -> Result<()> {
+    let mut file = BufReader::new(File::open(install_path)?);
+    match get_file_type(&mut file) {
+        FileType::AppImage => {
+            setup_portable_dir(
+                bin_name,
+                install_path,
+                options.portable,
+                options.portable_home,
+                options.portable_config,
+            )
+            .await
+        }
+        FileType::FlatImage => {
+            setup_portable_dir(
+                bin_name,
+                &flatimage_portable_path(install_path, bin_name),
+                None,
+                None,
+                options.portable_config,
+            )
+            .await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Moves portable directories created next to the previous install of a package over to the
+/// new install, so they survive updates.
+pub async fn move_portable_dirs(bin_name: &str, old_path: &Path, new_path: &Path) -> Result<()> {
+    for (old_dir, new_dir) in portable_dir_paths(bin_name, old_path, new_path) {
+        if old_dir.is_dir() && !old_dir.is_symlink() && !new_dir.exists() {
+            fs::rename(&old_dir, &new_dir).await.context(format!(
+                "Failed to move portable directory {}",
+                old_dir.to_string_lossy().color(Color::Blue)
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the portable directories next to `old_path`, paired with their location next to
+/// `new_path`.
+pub fn portable_dir_paths(
+    bin_name: &str,
+    old_path: &Path,
+    new_path: &Path,
+) -> Vec<(PathBuf, PathBuf)> {
+    let bases = [
+        (old_path.to_path_buf(), new_path.to_path_buf()),
+        (
+            flatimage_portable_path(old_path, bin_name),
+            flatimage_portable_path(new_path, bin_name),
+        ),
+    ];
+
+    bases
+        .iter()
+        .flat_map(|(old_base, new_base)| {
+            ["home", "config"]
+                .map(|ext| (old_base.with_extension(ext), new_base.with_extension(ext)))
+        })
+        .collect()
+}
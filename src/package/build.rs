@@ -84,7 +84,7 @@ impl BuildOutput {
             std::fs::copy(file.path(), &final_path)?;
         }
 
-        std::fs::copy(log_path, final_dir.join(format!("build.log")))?;
+        std::fs::copy(log_path, final_dir.join("build.log"))?;
         std::fs::remove_file(log_path)?;
 
         std::fs::remove_dir_all(&output.sbuild_outdir)?;
@@ -128,7 +128,7 @@ pub async fn init<P: AsRef<Path>>(file_path: P) -> Result<()> {
     let (tx, rx) = mpsc::channel();
     let tx_stderr = tx.clone();
 
-    let log_path = CACHE_PATH.join(&format!("{}.build.log", sbuild_id));
+    let log_path = CACHE_PATH.join(format!("{}.build.log", sbuild_id));
     let log_file = File::create(&log_path)?;
     let mut writer = BufWriter::new(log_file);
 
@@ -10,6 +10,7 @@ use termion::raw::IntoRawMode;
 use tokio::fs;
 
 use crate::core::{
+    config::CONFIG,
     constant::REGISTRY_PATH,
     util::{download, get_font_height, get_font_width},
 };
@@ -46,7 +47,7 @@ fn is_sixel_supported() -> Result<bool> {
     let mut buffer = Vec::new();
     let stdin = io::stdin();
 
-    for byte in stdin.bytes() {
+    for byte in stdin.lock().bytes() {
         let byte = byte?;
         if byte == b'c' {
             break;
@@ -185,12 +186,18 @@ pub async fn load_default_icon(icon_path: &str) -> Result<Vec<u8>> {
     Ok(content)
 }
 
-pub async fn get_package_image_string(resolved_package: &ResolvedPackage) -> String {
+/// Renders the package icon for the terminal. Returns `None` if neither the package icon
+/// nor the default icon of its collection is available.
+pub async fn get_package_image_string(resolved_package: &ResolvedPackage) -> Option<String> {
     let package = &resolved_package.package;
-    let icon = download(&package.icon, "icon", true).await;
+    let icon = if CONFIG.is_offline() {
+        None
+    } else {
+        download(&package.icon, "icon", true).await.ok()
+    };
     let icon = match icon {
-        Ok(icon) => icon,
-        Err(_) => load_default_icon(&format!(
+        Some(icon) => icon,
+        None => load_default_icon(&format!(
             "{}-{}.png",
             resolved_package.repo_name, resolved_package.collection
         ))
@@ -211,7 +218,7 @@ pub async fn get_package_image_string(resolved_package: &ResolvedPackage) -> Str
             .await
             .unwrap_or_default(),
         )
-        .unwrap(),
+        .ok()?,
     };
 
     if is_kitty_supported().unwrap_or(false) {
@@ -228,7 +235,7 @@ pub async fn get_package_image_string(resolved_package: &ResolvedPackage) -> Str
 
         let encoded = general_purpose::STANDARD.encode(&icon);
 
-        return build_transmit_sequence(&encoded);
+        return Some(build_transmit_sequence(&encoded));
     } else if is_sixel_supported().unwrap_or(false) {
         let img = img.resize_exact(
             image_width,
@@ -253,9 +260,9 @@ pub async fn get_package_image_string(resolved_package: &ResolvedPackage) -> Str
         .unwrap();
         let sixel_output = sixel_output.replace("\x1BPq", "\x1BP0;1q");
 
-        return sixel_output;
+        return Some(sixel_output);
     };
 
     let img = img.resize_exact(30, 30, image::imageops::FilterType::Lanczos3);
-    halfblock_string(&img).await
+    Some(halfblock_string(&img).await)
 }
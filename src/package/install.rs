@@ -7,7 +7,6 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use futures::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use reqwest::Url;
 use tokio::{
@@ -15,20 +14,26 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::Mutex,
 };
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use crate::{
     core::{
         color::{Color, ColorExt},
+        config::CONFIG,
         constant::{BIN_PATH, PACKAGES_PATH},
         file::{get_file_type, FileType},
-        util::{calculate_checksum, download_progress_style, validate_checksum},
+        http::download_file,
+        util::{calculate_checksum, download_progress_style},
     },
-    registry::installed::InstalledPackages,
+    registry::installed::{remove_versions, InstalledPackages, PortableOptions},
 };
 
 use super::{
-    appimage::{integrate_appimage, integrate_using_remote_files, setup_portable_dir},
+    appimage::{
+        applink_paths, flatimage_portable_path, integrate_appimage, integrate_using_remote_files,
+        move_portable_dirs, portable_dir_paths, remove_applinks, setup_portable_dir,
+    },
+    sandbox::{bin_target, create_bin_link},
     ResolvedPackage,
 };
 
@@ -40,10 +45,16 @@ pub struct Installer {
 
 impl Installer {
     pub fn new(package: &ResolvedPackage) -> Self {
+        // Partial downloads are kept per build, so one left by another version is never resumed.
+        let build = if package.package.bsum.is_empty() || package.package.bsum == "null" {
+            &package.package.version
+        } else {
+            &package.package.bsum
+        };
         let temp_path = PACKAGES_PATH
             .join("tmp")
             .join(package.package.full_name('-'))
-            .with_extension("part");
+            .join(format!("{}.part", build.replace('/', "-")));
         Self {
             resolved_package: package.to_owned(),
             install_path: Path::new("").to_path_buf(),
@@ -51,6 +62,7 @@ impl Installer {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn execute(
         &mut self,
         idx: usize,
@@ -60,6 +72,7 @@ impl Installer {
         portable_home: Option<String>,
         portable_config: Option<String>,
         multi_progress: Option<Arc<MultiProgress>>,
+        allow_checksum_mismatch: bool,
     ) -> Result<()> {
         let package = &self.resolved_package.package;
 
@@ -76,29 +89,73 @@ impl Installer {
                 prefix,
                 self.temp_path.to_string_lossy().color(Color::Blue)
             ))?;
+            remove_stale_partials(parent, &self.temp_path).await?;
         }
 
-        if Url::parse(&package.download_url).is_ok() {
-            self.download_remote_package(multi_progress.clone(), &prefix)
+        if let Some(path) = self.resolved_package.local_path()? {
+            self.copy_local_package(multi_progress.clone(), &prefix, &path)
                 .await?;
-        } else {
-            self.copy_local_package(multi_progress.clone(), &prefix)
+            self.verify_package(multi_progress.clone(), &prefix, allow_checksum_mismatch)
+                .await?;
+        } else if Url::parse(&package.download_url).is_ok() {
+            self.download_remote_package(multi_progress.clone(), &prefix, allow_checksum_mismatch)
                 .await?;
+        } else {
+            self.copy_local_package(
+                multi_progress.clone(),
+                &prefix,
+                Path::new(&package.download_url),
+            )
+            .await?;
         }
 
         let checksum = calculate_checksum(&self.temp_path).await?;
 
         self.install_path = package.get_install_path(&checksum);
-        if let Some(parent) = self.install_path.parent() {
-            fs::create_dir_all(parent).await.context(format!(
+
+        let given_portable = PortableOptions {
+            portable,
+            portable_home,
+            portable_config,
+        };
+        let (sandbox, portable, previous_install, disabled) = {
+            let installed_packages = installed_packages.lock().await;
+            let portable = if given_portable.is_empty() {
+                installed_packages.portable_options(&self.resolved_package)?
+            } else {
+                given_portable
+            };
+            let previous = installed_packages.find_package(&self.resolved_package);
+            (
+                installed_packages.sandbox_rules(&self.resolved_package)?,
+                portable,
+                previous.map(|installed| installed.get_install_path()),
+                previous.is_some_and(|installed| installed.disabled),
+            )
+        };
+
+        let transaction = InstallTransaction::begin(
+            &package.pkg,
+            &package.pkg_name,
+            &self.install_path,
+            previous_install,
+        )
+        .context(format!("{}: Failed to stage installation", prefix))?;
+
+        fs::create_dir_all(&transaction.install_dir)
+            .await
+            .context(format!(
                 "{}: Failed to create install directory {}",
                 prefix,
-                self.install_path.to_string_lossy().color(Color::Blue)
+                transaction.install_dir.to_string_lossy().color(Color::Blue)
             ))?;
-        }
 
         self.save_file().await?;
-        self.symlink_bin().await?;
+
+        if let Some(ref previous_install) = transaction.previous_install {
+            move_portable_dirs(&package.pkg_name, previous_install, &self.install_path).await?;
+        }
+        create_bin_link(&self.install_path, &transaction.bin_path, sandbox.as_ref()).await?;
 
         let mut file = BufReader::new(File::open(&self.install_path)?);
         let file_type = get_file_type(&mut file);
@@ -119,9 +176,9 @@ impl Installer {
                     setup_portable_dir(
                         &package.pkg_name,
                         &self.install_path,
-                        portable,
-                        portable_home,
-                        portable_config,
+                        portable.portable.clone(),
+                        portable.portable_home.clone(),
+                        portable.portable_config.clone(),
                     )
                     .await?;
                 } else if let Some(wb) = warn_bar {
@@ -139,14 +196,10 @@ impl Installer {
                 {
                     setup_portable_dir(
                         &package.pkg_name,
-                        Path::new(&format!(
-                            "{}/.{}",
-                            self.install_path.parent().unwrap().display(),
-                            package.pkg_name
-                        )),
+                        &flatimage_portable_path(&self.install_path, &package.pkg_name),
                         None,
                         None,
-                        portable_config,
+                        portable.portable_config.clone(),
                     )
                     .await?;
                 } else if let Some(wb) = warn_bar {
@@ -160,13 +213,23 @@ impl Installer {
             _ => {}
         }
 
-        {
-            let mut installed_packages = installed_packages.lock().await;
-            installed_packages
-                .register_package(&self.resolved_package, &checksum)
-                .await?;
+        // A disabled package stays disabled when reinstalled, like a pinned one stays pinned.
+        if disabled {
+            fs::remove_file(&transaction.bin_path).await?;
+            remove_applinks(&package.pkg, &package.pkg_name, &self.install_path).await?;
         }
 
+        // Registering is the last step that can fail. The files are kept right after it, with
+        // no await in between, so an interrupt can't leave the database ahead of the files.
+        let pruned = installed_packages.lock().await.register_package(
+            &self.resolved_package,
+            &checksum,
+            &portable,
+        )?;
+        transaction.commit();
+
+        remove_versions(&pruned).await;
+
         if let Some(mp) = multi_progress {
             let installed_progress = mp.insert_from_back(1, ProgressBar::new(0));
             installed_progress.set_style(ProgressStyle::default_bar().template("{msg}").unwrap());
@@ -196,96 +259,81 @@ impl Installer {
         &self,
         multi_progress: Option<Arc<MultiProgress>>,
         prefix: &str,
+        allow_checksum_mismatch: bool,
     ) -> Result<()> {
         let prefix = prefix.to_owned();
-        let package = &self.resolved_package.package;
-        let temp_path = &self.temp_path;
-        let client = reqwest::Client::new();
-        let downloaded_bytes = if temp_path.exists() {
-            let meta = fs::metadata(&temp_path).await?;
-            meta.len()
-        } else {
-            0
-        };
 
-        let response = client
-            .get(&package.download_url)
-            .header("Range", format!("bytes={}-", downloaded_bytes))
-            .send()
+        CONFIG
+            .ensure_online("download packages")
+            .map_err(|e| anyhow::anyhow!("{}: {}", prefix, e))?;
+
+        // Mirrors are tried in turn, each resuming from what the previous one downloaded.
+        let mut result = Ok(());
+        for url in self.resolved_package.download_urls() {
+            result = self
+                .download_from(&url, multi_progress.clone(), &prefix)
+                .await;
+            match result {
+                Ok(()) => {
+                    if let Some(repo) = self.resolved_package.repository() {
+                        repo.mark_mirror_working(&url);
+                    }
+                    break;
+                }
+                Err(ref e) => debug!("{}", e),
+            }
+        }
+        result?;
+
+        self.verify_package(multi_progress, &prefix, allow_checksum_mismatch)
             .await
-            .context(format!("{}: Failed to download package", prefix))?;
-        let total_size = response
-            .content_length()
-            .map(|cl| cl + downloaded_bytes)
-            .unwrap_or(0);
+    }
 
-        let download_progress = if let Some(ref mp) = multi_progress {
+    async fn download_from(
+        &self,
+        url: &str,
+        multi_progress: Option<Arc<MultiProgress>>,
+        prefix: &str,
+    ) -> Result<()> {
+        let download_progress = multi_progress.map(|mp| {
             let download_progress = mp.insert_from_back(1, ProgressBar::new(0));
-
             download_progress.set_style(download_progress_style(true));
+            download_progress.set_message(prefix.to_owned());
+            download_progress
+        });
 
-            download_progress.set_length(total_size);
-            download_progress.set_message(prefix.clone());
-            Some(download_progress)
-        } else {
-            None
-        };
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "{} Download failed {:?}",
-                prefix,
-                response.status().color(Color::Red),
-            ));
-        }
-
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&temp_path)
-            .await
-            .context(format!("{}: Failed to open temp file for writing", prefix))?;
-        let mut stream = response.bytes_stream();
-
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.context(format!("{}: Failed to read chunk", prefix))?;
-            file.write_all(&chunk).await?;
-            if let Some(ref pb) = download_progress {
-                pb.inc(chunk.len() as u64);
+        let result = download_file(url, &self.temp_path, download_progress.as_ref()).await;
+        if let Some(pb) = download_progress {
+            if result.is_ok() {
+                pb.finish();
+            } else {
+                pb.finish_and_clear();
             }
         }
-        if let Some(ref pb) = download_progress {
-            pb.finish();
-        }
-        file.flush().await?;
+        result.map_err(|e| anyhow::anyhow!("{}: {}", prefix, e))?;
+
+        Ok(())
+    }
 
-        let warn_bar = if let Some(mp) = multi_progress {
+    async fn verify_package(
+        &self,
+        multi_progress: Option<Arc<MultiProgress>>,
+        prefix: &str,
+        allow_checksum_mismatch: bool,
+    ) -> Result<()> {
+        let warning = self
+            .resolved_package
+            .package
+            .verify_checksum(&self.temp_path, allow_checksum_mismatch)
+            .await?;
+        if let (Some(warning), Some(mp)) = (warning, multi_progress) {
             let warn_bar = mp.insert_from_back(1, ProgressBar::new(0));
             warn_bar.set_style(ProgressStyle::default_bar().template("{msg}").unwrap());
-            Some(warn_bar)
-        } else {
-            None
-        };
-        if package.bsum == "null" {
-            if let Some(wb) = warn_bar {
-                wb.finish_with_message(format!(
-                    "{}: {}",
-                    prefix,
-                    "Missing checksum. Installing anyway.".color(Color::BrightYellow)
-                ));
-            }
-        } else {
-            let result = validate_checksum(&package.bsum, &self.temp_path).await;
-            if result.is_err() {
-                if let Some(wb) = warn_bar {
-                    wb.finish_with_message(format!(
-                        "{}: {}",
-                        prefix,
-                        "Checksum verification failed. Installing anyway."
-                            .color(Color::BrightYellow)
-                    ));
-                }
-            }
+            warn_bar.finish_with_message(format!(
+                "{}: {}",
+                prefix,
+                warning.color(Color::BrightYellow)
+            ));
         }
 
         Ok(())
@@ -295,16 +343,19 @@ impl Installer {
         &self,
         multi_progress: Option<Arc<MultiProgress>>,
         prefix: &str,
+        source: &Path,
     ) -> Result<()> {
         let temp_path = &self.temp_path;
         let prefix = prefix.to_owned();
-        let package = &self.resolved_package.package;
 
         let download_progress = if let Some(mp) = multi_progress {
             let download_progress = mp.insert_from_back(1, ProgressBar::new(0));
             download_progress.set_style(download_progress_style(true));
 
-            let total_size = package.size.parse::<u64>().unwrap_or_default();
+            let total_size = fs::metadata(source)
+                .await
+                .map(|meta| meta.len())
+                .unwrap_or_default();
             download_progress.set_length(total_size);
             download_progress.set_message(prefix.clone());
             Some(download_progress)
@@ -314,11 +365,16 @@ impl Installer {
 
         let mut file = fs::OpenOptions::new()
             .create(true)
-            .append(true)
+            .write(true)
+            .truncate(true)
             .open(&temp_path)
             .await
             .context(format!("{}: Failed to open temp file for writing", prefix))?;
-        let mut source = fs::File::open(&package.download_url).await?;
+        let mut source = fs::File::open(source).await.context(format!(
+            "{}: Failed to open {}",
+            prefix,
+            source.to_string_lossy().color(Color::Blue)
+        ))?;
         let mut buffer = vec![0u8; 8096];
 
         while let Ok(n) = source.read(&mut buffer).await {
@@ -351,35 +407,163 @@ impl Installer {
 
         Ok(())
     }
+}
 
-    async fn symlink_bin(&self) -> Result<()> {
-        let package = &self.resolved_package.package;
-        let install_path = &self.install_path;
-        let symlink_path = &BIN_PATH.join(&package.pkg_name);
-        if symlink_path.exists() {
-            if let Ok(link) = symlink_path.read_link() {
-                if *install_path == link {
-                    return Ok(());
+/// Removes partial downloads in `dir` left by other versions than the one downloaded to
+/// `temp_path`.
+async fn remove_stale_partials(dir: &Path, temp_path: &Path) -> Result<()> {
+    let Some(current) = temp_path.file_name() else {
+        return Ok(());
+    };
+
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry
+            .file_name()
+            .as_encoded_bytes()
+            .starts_with(current.as_encoded_bytes())
+        {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Changes made while installing a package. Unless committed, they are undone on drop, so
+/// the previous install is restored when any step fails or the install is interrupted.
+struct InstallTransaction {
+    name: String,
+    bin_name: String,
+    install_path: PathBuf,
+    install_dir: PathBuf,
+    /// Existing install at the same path, moved aside.
+    backup_dir: Option<PathBuf>,
+    /// Binary of the previous install.
+    previous_install: Option<PathBuf>,
+    bin_path: PathBuf,
+    /// Existing binary symlink or wrapper, moved aside.
+    bin_backup: Option<PathBuf>,
+    committed: bool,
+}
+
+impl InstallTransaction {
+    fn begin(
+        name: &str,
+        bin_name: &str,
+        install_path: &Path,
+        previous_install: Option<PathBuf>,
+    ) -> Result<Self> {
+        let install_dir = install_path.parent().unwrap().to_path_buf();
+        let bin_path = BIN_PATH.join(bin_name);
+
+        let mut transaction = Self {
+            name: name.to_owned(),
+            bin_name: bin_name.to_owned(),
+            install_path: install_path.to_path_buf(),
+            install_dir: install_dir.clone(),
+            backup_dir: None,
+            previous_install,
+            bin_path: bin_path.clone(),
+            bin_backup: None,
+            committed: false,
+        };
+
+        if install_dir.exists() {
+            let backup_dir = PathBuf::from(format!("{}.bak", install_dir.display()));
+            if backup_dir.exists() {
+                std::fs::remove_dir_all(&backup_dir)?;
+            }
+            std::fs::rename(&install_dir, &backup_dir)?;
+
+            if transaction.previous_install.as_deref() == Some(install_path) {
+                transaction.previous_install = Some(backup_dir.join(bin_name));
+            }
+            transaction.backup_dir = Some(backup_dir);
+        }
+
+        if bin_path.exists() || bin_path.is_symlink() {
+            let bin_backup = BIN_PATH.join(format!(".{}.soar-bak", bin_name));
+            std::fs::rename(&bin_path, &bin_backup)?;
+            transaction.bin_backup = Some(bin_backup);
+        }
+
+        Ok(transaction)
+    }
+
+    /// Keeps the new install. The previous version is left in place for rollback, its
+    /// cleanup is up to the installed package store.
+    fn commit(mut self) {
+        self.committed = true;
+
+        if let Some(ref bin_backup) = self.bin_backup {
+            let _ = std::fs::remove_file(bin_backup);
+        }
+        if let Some(ref backup_dir) = self.backup_dir {
+            let _ = std::fs::remove_dir_all(backup_dir);
+        }
+    }
+
+    fn rollback(&self) {
+        warn!(
+            "Rolling back installation of {}",
+            (&self.name).color(Color::Blue)
+        );
+
+        for (original_path, link_path) in
+            applink_paths(&self.name, &self.bin_name, &self.install_path)
+        {
+            if link_path
+                .read_link()
+                .is_ok_and(|target| target == original_path)
+            {
+                let _ = std::fs::remove_file(&link_path);
+            }
+        }
+
+        if bin_target(&self.bin_path).is_some_and(|target| target == self.install_path) {
+            let _ = std::fs::remove_file(&self.bin_path);
+        }
+        if let Some(ref bin_backup) = self.bin_backup {
+            let _ = std::fs::rename(bin_backup, &self.bin_path);
+        }
+
+        if let Some(ref previous_install) = self.previous_install {
+            for (old_dir, new_dir) in
+                portable_dir_paths(&self.bin_name, previous_install, &self.install_path)
+            {
+                if new_dir.is_dir() && !new_dir.is_symlink() && !old_dir.exists() {
+                    let _ = std::fs::rename(&new_dir, &old_dir);
                 }
-                if let Ok(parent) = link.strip_prefix(&*PACKAGES_PATH) {
-                    let package_path = parent.parent().unwrap().to_string_lossy();
-                    let package_name = &package_path[..8];
+            }
+        }
 
-                    if package_name == package.full_name('-') {
-                        fs::remove_dir_all(link.parent().unwrap()).await?;
-                    }
-                };
+        let _ = std::fs::remove_dir_all(&self.install_dir);
+        if let Some(ref backup_dir) = self.backup_dir {
+            let _ = std::fs::rename(backup_dir, &self.install_dir);
+        }
+
+        let previous_install = match self.backup_dir {
+            Some(_) => Some(&self.install_path),
+            None => self.previous_install.as_ref(),
+        };
+        if let Some(previous_install) = previous_install {
+            for (original_path, link_path) in
+                applink_paths(&self.name, &self.bin_name, previous_install)
+            {
+                if original_path.exists() && !link_path.is_symlink() {
+                    let _ = std::fs::create_dir_all(link_path.parent().unwrap());
+                    let _ = std::os::unix::fs::symlink(&original_path, &link_path);
+                }
             }
-            fs::remove_file(symlink_path).await?;
         }
-        fs::symlink(&install_path, &symlink_path)
-            .await
-            .context(format!(
-                "Failed to link {} to {}",
-                install_path.to_string_lossy(),
-                symlink_path.to_string_lossy()
-            ))?;
+    }
+}
 
-        Ok(())
+impl Drop for InstallTransaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.rollback();
+        }
     }
 }
@@ -1,12 +1,14 @@
-mod appimage;
+pub mod appimage;
 pub mod build;
 pub mod image;
 mod install;
 pub mod remove;
 pub mod run;
+pub mod sandbox;
 pub mod update;
 
 use std::{
+    fmt,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -15,9 +17,17 @@ use anyhow::Result;
 use indicatif::MultiProgress;
 use install::Installer;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{fs, sync::Mutex};
 
-use crate::{core::constant::PACKAGES_PATH, registry::installed::InstalledPackages};
+use crate::{
+    core::{
+        color::{Color, ColorExt},
+        config::{MissingChecksum, Repository, CONFIG},
+        constant::PACKAGES_PATH,
+        util::{calculate_checksum, file_url_path},
+    },
+    registry::installed::InstalledPackages,
+};
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct Package {
@@ -50,6 +60,41 @@ pub struct ResolvedPackage {
 }
 
 impl ResolvedPackage {
+    /// Repository the package comes from.
+    pub fn repository(&self) -> Option<&'static Repository> {
+        CONFIG
+            .repositories
+            .iter()
+            .find(|repo| repo.name == self.repo_name)
+    }
+
+    /// Path of the package on disk if its download URL points to a local file. Only packages of
+    /// local repositories may do so, metadata served from elsewhere can't make soar use
+    /// arbitrary files from disk.
+    pub fn local_path(&self) -> Result<Option<PathBuf>> {
+        let Some(path) = file_url_path(&self.package.download_url) else {
+            return Ok(None);
+        };
+        if self.repository().and_then(Repository::local_path).is_none() {
+            anyhow::bail!(
+                "Refusing to use {} from {}, only local repositories may point to local files",
+                self.package.download_url.as_str().color(Color::Blue),
+                self.repo_name.as_str().color(Color::Cyan)
+            );
+        }
+
+        Ok(Some(path))
+    }
+
+    /// URLs the package can be downloaded from, one for each mirror serving it.
+    pub fn download_urls(&self) -> Vec<String> {
+        match self.repository() {
+            Some(repo) => repo.mirror_urls(&self.package.download_url),
+            None => vec![self.package.download_url.clone()],
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn install(
         &self,
         idx: usize,
@@ -59,6 +104,7 @@ impl ResolvedPackage {
         portable_home: Option<String>,
         portable_config: Option<String>,
         multi_progress: Option<Arc<MultiProgress>>,
+        allow_checksum_mismatch: bool,
     ) -> Result<()> {
         let mut installer = Installer::new(self);
         installer
@@ -70,13 +116,81 @@ impl ResolvedPackage {
                 portable_home,
                 portable_config,
                 multi_progress,
+                allow_checksum_mismatch,
             )
             .await?;
         Ok(())
     }
 }
 
+/// Error returned when a downloaded package doesn't match the checksum published in its
+/// metadata.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub package: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: Checksum verification failed. Expected {}, got {}. Use --allow-checksum-mismatch to install it anyway.",
+            (&self.package).color(Color::Blue),
+            (&self.expected).color(Color::Green),
+            (&self.actual).color(Color::Red)
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
 impl Package {
+    /// Verifies the downloaded file at `path` against the published checksum. Files failing
+    /// verification are deleted unless the mismatch is allowed, either by `strict_checksum`
+    /// being turned off or by `allow_mismatch`. Returns a warning to show if the file is
+    /// kept without a successful verification.
+    pub async fn verify_checksum(
+        &self,
+        path: &Path,
+        allow_mismatch: bool,
+    ) -> Result<Option<&'static str>> {
+        if self.bsum.is_empty() || self.bsum == "null" {
+            return match CONFIG.missing_checksum.unwrap_or_default() {
+                MissingChecksum::Allow => Ok(None),
+                MissingChecksum::Warn => Ok(Some("Missing checksum. Installing anyway.")),
+                MissingChecksum::Deny if allow_mismatch => {
+                    Ok(Some("Missing checksum. Installing anyway."))
+                }
+                MissingChecksum::Deny => {
+                    let _ = fs::remove_file(path).await;
+                    Err(anyhow::anyhow!(
+                        "{}: Missing checksum. Use --allow-checksum-mismatch to install it anyway.",
+                        self.full_name('/').color(Color::Blue)
+                    ))
+                }
+            };
+        }
+
+        let checksum = calculate_checksum(path).await?;
+        if checksum == self.bsum {
+            return Ok(None);
+        }
+
+        if allow_mismatch || !CONFIG.strict_checksum.unwrap_or(true) {
+            return Ok(Some("Checksum verification failed. Installing anyway."));
+        }
+
+        let _ = fs::remove_file(path).await;
+        Err(ChecksumMismatch {
+            package: self.full_name('/'),
+            expected: self.bsum.clone(),
+            actual: checksum,
+        }
+        .into())
+    }
+
     pub fn get_install_dir(&self, checksum: &str) -> PathBuf {
         PACKAGES_PATH.join(format!("{}-{}", &checksum[..8], self.full_name('-')))
     }
@@ -100,9 +214,25 @@ pub struct PackageQuery {
     pub name: String,
     pub family: Option<String>,
     pub collection: Option<String>,
+    pub repo_name: Option<String>,
 }
 
+impl PackageQuery {
+    /// Whether `repo_name` satisfies the repository qualifier of the query.
+    pub fn matches_repo(&self, repo_name: &str) -> bool {
+        self.repo_name
+            .as_ref()
+            .is_none_or(|name| name.eq_ignore_ascii_case(repo_name))
+    }
+}
+
+/// Parses a package query of the form `[repo:][family/]name[#collection]`.
 pub fn parse_package_query(query: &str) -> PackageQuery {
+    let (repo_name, query) = query
+        .split_once(':')
+        .map(|(r, q)| ((!r.is_empty()).then(|| r.to_owned()), q))
+        .unwrap_or((None, query));
+
     let query = query.to_lowercase();
     let (base_query, collection) = query
         .rsplit_once('#')
@@ -118,6 +248,7 @@ pub fn parse_package_query(query: &str) -> PackageQuery {
         name,
         family,
         collection,
+        repo_name,
     }
 }
 
@@ -137,3 +268,33 @@ pub fn gen_package_info(name: &str, path: &Path, size: u64) -> ResolvedPackage {
         package,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_package_query_reads_every_qualifier() {
+        let query = parse_package_query("PkgForge:Family/Name#Bin");
+
+        assert_eq!(query.repo_name.as_deref(), Some("PkgForge"));
+        assert_eq!(query.family.as_deref(), Some("family"));
+        assert_eq!(query.name, "name");
+        assert_eq!(query.collection.as_deref(), Some("bin"));
+        assert!(query.matches_repo("pkgforge"));
+        assert!(!query.matches_repo("other"));
+    }
+
+    #[test]
+    fn parse_package_query_without_repo_matches_any_repo() {
+        for query in ["name", ":name"] {
+            let query = parse_package_query(query);
+
+            assert_eq!(query.repo_name, None);
+            assert_eq!(query.family, None);
+            assert_eq!(query.name, "name");
+            assert_eq!(query.collection, None);
+            assert!(query.matches_repo("pkgforge"));
+        }
+    }
+}
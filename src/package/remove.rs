@@ -9,7 +9,7 @@ use crate::{
         color::{Color, ColorExt},
         constant::BIN_PATH,
     },
-    package::appimage::remove_applinks,
+    package::{appimage::remove_applinks, sandbox::bin_target},
     registry::installed::{InstalledPackage, InstalledPackages},
 };
 
@@ -45,11 +45,8 @@ impl Remover {
     pub async fn remove_symlink(&self, install_path: &Path) -> Result<()> {
         let package = &self.package;
         let symlink_path = BIN_PATH.join(&package.bin_name);
-        if symlink_path.exists() {
-            let target = fs::read_link(&symlink_path).await?;
-            if target == install_path {
-                fs::remove_file(&symlink_path).await?;
-            }
+        if bin_target(&symlink_path).is_some_and(|target| target == install_path) {
+            fs::remove_file(&symlink_path).await?;
         }
 
         Ok(())
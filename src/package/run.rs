@@ -1,32 +1,44 @@
 use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, process::Command};
 
 use anyhow::{Context, Result};
-use futures::StreamExt;
-use tokio::{fs, io::AsyncWriteExt};
-use tracing::{error, info, warn};
+use indicatif::ProgressBar;
+use tokio::fs;
+use tracing::{debug, info, warn};
 
 use crate::core::{
     color::{Color, ColorExt},
-    util::{format_bytes, validate_checksum},
+    config::CONFIG,
+    http::download_file,
+    util::download_progress_style,
 };
 
-use super::ResolvedPackage;
+use super::{sandbox::SandboxRules, ResolvedPackage};
 
 pub struct Runner {
     args: Vec<String>,
     resolved_package: ResolvedPackage,
     install_path: PathBuf,
     temp_path: PathBuf,
+    sandbox: Option<SandboxRules>,
+    allow_checksum_mismatch: bool,
 }
 
 impl Runner {
-    pub fn new(package: &ResolvedPackage, install_path: PathBuf, args: &[String]) -> Self {
+    pub fn new(
+        package: &ResolvedPackage,
+        install_path: PathBuf,
+        args: &[String],
+        sandbox: Option<SandboxRules>,
+        allow_checksum_mismatch: bool,
+    ) -> Self {
         let temp_path = install_path.with_extension("part");
         Self {
             args: args.to_owned(),
             resolved_package: package.to_owned(),
             install_path,
             temp_path,
+            sandbox,
+            allow_checksum_mismatch,
         }
     }
 
@@ -49,79 +61,68 @@ impl Runner {
             }
         }
 
-        let client = reqwest::Client::new();
-        let downloaded_bytes = if self.temp_path.exists() {
-            let meta = fs::metadata(&self.temp_path).await?;
-            meta.len()
-        } else {
-            0
-        };
-
-        let response = client
-            .get(&package.download_url)
-            .header("Range", format!("bytes={}-", downloaded_bytes))
-            .send()
-            .await
-            .context(format!("{} Failed to download package", package_name))?;
-        let total_size = response
-            .content_length()
-            .map(|cl| cl + downloaded_bytes)
-            .unwrap_or(0);
-        println!(
-            "{}: Downloading package [{}]",
-            package_name.color(Color::Blue),
-            format_bytes(total_size).color(Color::Yellow)
-        );
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "{}: Download failed {:?}",
+        if let Some(path) = self.resolved_package.local_path()? {
+            fs::copy(&path, &self.temp_path).await.context(format!(
+                "{}: Failed to copy package from {}",
                 package_name.color(Color::Blue),
-                response.status().color(Color::Red)
-            ));
+                path.to_string_lossy().color(Color::Blue)
+            ))?;
+        } else {
+            self.download_package().await?;
         }
 
+        if let Some(warning) = package
+            .verify_checksum(&self.temp_path, self.allow_checksum_mismatch)
+            .await?
         {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&self.temp_path)
-                .await
-                .context(format!(
-                    "{}: Failed to open temp file for writing",
-                    package_name.color(Color::Blue)
-                ))?;
+            warn!("{}: {}", package_name.color(Color::Blue), warning);
+        }
 
-            let mut stream = response.bytes_stream();
+        self.save_file().await?;
+        self.run().await?;
 
-            while let Some(chunk) = stream.next().await {
-                let chunk = chunk.context(format!(
-                    "{}: Failed to read chunk",
-                    package_name.color(Color::Blue)
-                ))?;
-                file.write_all(&chunk).await?;
+        Ok(())
+    }
+
+    async fn download_package(&self) -> Result<()> {
+        let package_name = &self.resolved_package.package.full_name('/');
+
+        CONFIG
+            .ensure_online("download packages")
+            .map_err(|e| anyhow::anyhow!("{}: {}", package_name.color(Color::Blue), e))?;
+
+        let mut result = Ok(());
+        for url in self.resolved_package.download_urls() {
+            result = self.download_from(&url).await;
+            match result {
+                Ok(()) => {
+                    if let Some(repo) = self.resolved_package.repository() {
+                        repo.mark_mirror_working(&url);
+                    }
+                    break;
+                }
+                Err(ref e) => debug!("{}", e),
             }
-            file.flush().await?;
         }
 
-        if package.bsum == "null" {
-            warn!(
-                "Missing checksum for {}. Installing anyway.",
-                package.full_name('/').color(Color::Blue)
-            );
+        result
+    }
+
+    async fn download_from(&self, url: &str) -> Result<()> {
+        let package_name = &self.resolved_package.package.full_name('/');
+
+        println!("{}: Downloading package", package_name.color(Color::Blue));
+
+        let progress_bar = ProgressBar::new(0);
+        progress_bar.set_style(download_progress_style(false));
+
+        let result = download_file(url, &self.temp_path, Some(&progress_bar)).await;
+        if result.is_ok() {
+            progress_bar.finish();
         } else {
-            let result = validate_checksum(&package.bsum, &self.temp_path).await;
-            if result.is_err() {
-                error!(
-                    "{}: Checksum verification failed.",
-                    package_name.color(Color::Blue)
-                );
-            }
+            progress_bar.finish_and_clear();
         }
-
-        self.save_file().await?;
-        self.run().await?;
+        result.map_err(|e| anyhow::anyhow!("{}: {}", package_name.color(Color::Blue), e))?;
 
         Ok(())
     }
@@ -140,7 +141,16 @@ impl Runner {
     }
 
     async fn run(&self) -> Result<()> {
-        Command::new(&self.install_path).args(&self.args).status()?;
+        let mut command = match self.sandbox {
+            Some(ref rules) => rules.command(&self.install_path)?,
+            None => Command::new(&self.install_path),
+        };
+        command.args(&self.args).status().with_context(|| {
+            format!(
+                "Failed to run {}",
+                self.install_path.to_string_lossy().color(Color::Blue)
+            )
+        })?;
 
         Ok(())
     }
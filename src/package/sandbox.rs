@@ -0,0 +1,285 @@
+use std::{
+    env,
+    ffi::{CStr, OsString},
+    fs::{File, Permissions},
+    io::{self, BufReader},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use libc::{getgid, getuid, unshare, CLONE_NEWNET, CLONE_NEWUSER};
+use tokio::fs;
+
+use crate::core::{
+    color::{Color, ColorExt},
+    file::{get_file_type, FileType},
+    util::build_path,
+};
+
+/// Paths sandboxed packages can always read and execute from.
+const SYSTEM_READ_PATHS: [&str; 12] = [
+    "/bin", "/etc", "/lib", "/lib32", "/lib64", "/opt", "/proc", "/run", "/sbin", "/sys", "/usr",
+    "/nix",
+];
+
+/// Paths sandboxed packages can always write to, along with the temporary directory.
+const SYSTEM_WRITE_PATHS: [&str; 2] = ["/dev", "/tmp"];
+
+/// Extended attribute holding the install path a sandbox wrapper runs.
+const TARGET_XATTR: &str = "user.soar.target";
+
+/// Extended attribute holding the soar binary a sandbox wrapper launches.
+const EXE_XATTR: &str = "user.soar.exe";
+
+/// Filesystem and network restrictions a package runs with.
+#[derive(Debug, Clone)]
+pub struct SandboxRules {
+    /// Additional paths the package can read from.
+    pub fs_read: Vec<String>,
+
+    /// Additional paths the package can read from and write to.
+    pub fs_write: Vec<String>,
+
+    /// Whether the package has network access.
+    pub net: bool,
+}
+
+impl Default for SandboxRules {
+    fn default() -> Self {
+        Self {
+            fs_read: Vec::new(),
+            fs_write: Vec::new(),
+            net: true,
+        }
+    }
+}
+
+impl SandboxRules {
+    /// Builds a command that runs `path` confined by the rules. Landlock is applied in the
+    /// child right before exec, along with a new user and network namespace if network access
+    /// is cut off.
+    ///
+    /// Landlock requires `no_new_privs`, which keeps the setuid `fusermount` from mounting
+    /// AppImages, so they are run with `APPIMAGE_EXTRACT_AND_RUN` and extract themselves to
+    /// the temporary directory instead.
+    pub fn command(&self, path: &Path) -> Result<Command> {
+        let abi = ABI::V5;
+
+        let read_paths = SYSTEM_READ_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(path.parent().map(Path::to_path_buf))
+            .chain(
+                self.fs_read
+                    .iter()
+                    .map(|p| build_path(p))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+            .collect::<Vec<_>>();
+        let write_paths = SYSTEM_WRITE_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(std::iter::once(env::temp_dir()))
+            .chain(
+                self.fs_write
+                    .iter()
+                    .map(|p| build_path(p))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+            .collect::<Vec<_>>();
+
+        let ruleset = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?
+            .add_rules(path_beneath_rules(read_paths, AccessFs::from_read(abi)))?
+            .add_rules(path_beneath_rules(write_paths, AccessFs::from_all(abi)))?;
+
+        let mut ruleset = Some(ruleset);
+        let isolate_net = !self.net;
+        let uid_map = format!("{0} {0} 1", unsafe { getuid() });
+        let gid_map = format!("{0} {0} 1", unsafe { getgid() });
+
+        let mut command = Command::new(path);
+        if is_appimage(path) {
+            command.env("APPIMAGE_EXTRACT_AND_RUN", "1");
+        }
+        // SAFETY: the closure doesn't allocate. It only makes raw syscalls with buffers
+        // prepared before forking, and Landlock only issues `prctl` and `landlock_*` syscalls
+        // when restricting.
+        unsafe {
+            command.pre_exec(move || {
+                if isolate_net {
+                    if unshare(CLONE_NEWUSER | CLONE_NEWNET) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    write_proc_file(c"/proc/self/setgroups", b"deny")?;
+                    write_proc_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+                    write_proc_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+                }
+
+                if let Some(ruleset) = ruleset.take() {
+                    let status = ruleset
+                        .restrict_self()
+                        .map_err(|_| io::Error::from_raw_os_error(libc::EPERM))?;
+                    if status.ruleset == RulesetStatus::NotEnforced {
+                        return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+/// Writes `content` to a file in `/proc` with raw syscalls, as `std::fs::write` allocates and
+/// can't be used between fork and exec.
+fn write_proc_file(path: &CStr, content: &[u8]) -> io::Result<()> {
+    // SAFETY: `path` is NUL-terminated and `content` is valid for its length.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, content.as_ptr().cast(), content.len());
+        let result = if written < 0 {
+            Err(io::Error::last_os_error())
+        } else if written as usize != content.len() {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+fn is_appimage(path: &Path) -> bool {
+    File::open(path)
+        .map(|file| get_file_type(&mut BufReader::new(file)) == FileType::AppImage)
+        .unwrap_or(false)
+}
+
+/// Runs `command` inside the sandbox and exits with its exit code.
+pub fn exec(command: &[String], rules: &SandboxRules) -> Result<()> {
+    let path = Path::new(&command[0]);
+    let status = rules
+        .command(path)?
+        .args(&command[1..])
+        .status()
+        .with_context(|| {
+            format!(
+                "Failed to run {} in sandbox. Run `soar health` to check for user namespace and Landlock support.",
+                path.display().color(Color::Blue)
+            )
+        })?;
+
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Links the binary of a package at `bin_path`. Sandboxed packages get a wrapper script that
+/// launches them through `soar sandbox exec`, others a plain symlink.
+pub async fn create_bin_link(
+    install_path: &Path,
+    bin_path: &Path,
+    sandbox: Option<&SandboxRules>,
+) -> Result<()> {
+    if bin_path.file_name().is_some_and(|name| name == "soar") {
+        return Err(anyhow::anyhow!(
+            "Refusing to link {} as it would shadow soar",
+            install_path.to_string_lossy().color(Color::Blue)
+        ));
+    }
+
+    let Some(rules) = sandbox else {
+        return fs::symlink(install_path, bin_path).await.context(format!(
+            "Failed to link {} to {}",
+            install_path.to_string_lossy().color(Color::Blue),
+            bin_path.to_string_lossy().color(Color::Blue)
+        ));
+    };
+
+    // The wrapper runs the absolute path of soar rather than whatever is found in PATH, so
+    // nothing else can stand in for it and skip the sandbox.
+    let soar = soar_exe()?;
+    let mut args = vec![format!(
+        "exec {} sandbox exec",
+        shell_quote(&soar.to_string_lossy())
+    )];
+    for path in &rules.fs_read {
+        args.push(format!("--read {}", shell_quote(path)));
+    }
+    for path in &rules.fs_write {
+        args.push(format!("--write {}", shell_quote(path)));
+    }
+    if !rules.net {
+        args.push("--no-net".to_owned());
+    }
+    args.push(format!(
+        "-- {} \"$@\"",
+        shell_quote(&install_path.to_string_lossy())
+    ));
+
+    let script = format!("#!/bin/sh\n{}\n", args.join(" "));
+    fs::write(bin_path, script).await.context(format!(
+        "Failed to write sandbox wrapper {}",
+        bin_path.to_string_lossy().color(Color::Blue)
+    ))?;
+    fs::set_permissions(bin_path, Permissions::from_mode(0o755)).await?;
+    xattr::set(bin_path, "user.managed_by", b"soar")?;
+    xattr::set(bin_path, TARGET_XATTR, install_path.as_os_str().as_bytes())?;
+    xattr::set(bin_path, EXE_XATTR, soar.as_os_str().as_bytes())?;
+
+    Ok(())
+}
+
+/// Returns whether `bin_path` is a sandbox wrapper launching another soar binary than the
+/// running one, which happens once soar is moved or updated.
+pub fn is_stale_wrapper(bin_path: &Path) -> bool {
+    if bin_path.is_symlink() {
+        return false;
+    }
+    let Ok(Some(exe)) = xattr::get(bin_path, EXE_XATTR) else {
+        return xattr::get(bin_path, TARGET_XATTR).is_ok_and(|target| target.is_some());
+    };
+    soar_exe().is_ok_and(|soar| soar.as_os_str().as_bytes() != exe)
+}
+
+/// Returns the absolute path of the running soar binary. After `soar self update` replaced it,
+/// the kernel reports the old binary as deleted while the new one lives at the same path.
+fn soar_exe() -> Result<PathBuf> {
+    let exe = env::current_exe().context("Failed to get path to soar")?;
+    Ok(exe
+        .to_str()
+        .and_then(|exe| exe.strip_suffix(" (deleted)"))
+        .map(PathBuf::from)
+        .unwrap_or(exe))
+}
+
+/// Returns the install path a binary symlink or sandbox wrapper points to.
+pub fn bin_target(bin_path: &Path) -> Option<PathBuf> {
+    if bin_path.is_symlink() {
+        bin_path.read_link().ok()
+    } else {
+        xattr::get(bin_path, TARGET_XATTR)
+            .ok()
+            .flatten()
+            .map(|target| PathBuf::from(OsString::from_vec(target)))
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     core::color::{Color, ColorExt},
@@ -23,7 +23,12 @@ impl Updater {
         }
     }
 
-    pub async fn execute(&self, registry: &PackageRegistry, quiet: bool) -> Result<()> {
+    pub async fn execute(
+        &self,
+        registry: &PackageRegistry,
+        quiet: bool,
+        allow_checksum_mismatch: bool,
+    ) -> Result<()> {
         let installed_guard = registry.installed_packages.lock().await;
         let packages = match &self.package_names {
             Some(r) => {
@@ -40,6 +45,7 @@ impl Updater {
                     let pkg = parse_package_query(&installed.name);
                     let query = PackageQuery {
                         collection: Some(installed.collection.clone()),
+                        repo_name: Some(installed.repo_name.clone()),
                         ..pkg
                     };
                     registry
@@ -60,7 +66,19 @@ impl Updater {
                 .find(|installed| installed.full_name('-') == package.package.full_name('-'))
             {
                 if installed_package.checksum != package.package.bsum {
-                    packages_to_update.push(package);
+                    if installed_package.pinned {
+                        warn!(
+                            "{} is pinned. Skipping update.",
+                            package.package.full_name('/').color(Color::Blue)
+                        );
+                    } else if installed_package.disabled {
+                        warn!(
+                            "{} is disabled. Skipping update.",
+                            package.package.full_name('/').color(Color::Blue)
+                        );
+                    } else {
+                        packages_to_update.push(package);
+                    }
                 }
             } else {
                 error!(
@@ -100,6 +118,7 @@ impl Updater {
                         } else {
                             Some(multi_progress.clone())
                         },
+                        allow_checksum_mismatch,
                     )
                     .await?;
                 update_count += 1;
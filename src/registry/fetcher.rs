@@ -1,75 +1,70 @@
-use std::{collections::HashMap, env::consts::ARCH, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env::consts::ARCH,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
-use futures::future::try_join_all;
-use serde::Deserialize;
-use tokio::fs;
+use flate2::read::GzDecoder;
+use futures::{future::try_join_all, StreamExt};
+use minisign_verify::{PublicKey, Signature, StreamVerifier};
+use reqwest::{header::CONTENT_ENCODING, Url};
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{fs, sync::mpsc, task::spawn_blocking};
+use tracing::{debug, info, warn};
 
 use crate::{
     core::{
         color::{Color, ColorExt},
         config::Repository,
-        constant::REGISTRY_PATH,
-        util::download,
+        constant::{GZIP_MAGIC_BYTES, REGISTRY_PATH, ZSTD_MAGIC_BYTES},
+        http::{self, StatusError},
+        util::{calculate_checksum, download, file_url_path, format_bytes, parse_size},
     },
     package::Package,
 };
 
 pub struct MetadataFetcher;
 
+/// Maximum number of deltas applied in one sync before falling back to a full fetch.
+const MAX_DELTA_CHAIN: usize = 30;
+
 #[derive(Deserialize)]
 struct RepositoryResponse {
     #[serde(flatten)]
     collection: HashMap<String, Vec<Package>>,
 }
 
-impl MetadataFetcher {
-    pub fn new() -> Self {
-        Self
-    }
+/// Changes between two versions of the metadata of a repository. Repositories publish it as
+/// `<metadata>.delta/<checksum>.json`, where `checksum` is the `.bsum` of the older version,
+/// along with a `.bsum` file of its own.
+/// Packages are matched by collection, family, `pkg` and `pkg_name`.
+#[derive(Default, Deserialize, Serialize)]
+pub struct MetadataDelta {
+    /// Checksum of the metadata after applying the delta.
+    pub checksum: String,
 
-    pub async fn execute(&self, repository: &Repository) -> Result<Vec<u8>> {
-        let url = format!(
-            "{}/{}",
-            repository.url,
-            repository
-                .metadata
-                .to_owned()
-                .unwrap_or("metadata.json".to_owned())
-        );
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub added: BTreeMap<String, Vec<Package>>,
 
-        let content = download(&url, "metadata", false).await?;
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub changed: BTreeMap<String, Vec<Package>>,
 
-        let parsed: RepositoryResponse =
-            serde_json::from_slice(&content).context("Failed to parse metadata json")?;
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub removed: BTreeMap<String, Vec<Package>>,
+}
 
-        let metadata: HashMap<String, HashMap<String, Vec<Package>>> = parsed
-            .collection
-            .iter()
-            .map(|(key, packages)| {
-                let package_map: HashMap<String, Vec<Package>> =
-                    packages.iter().fold(HashMap::new(), |mut acc, package| {
-                        acc.entry(package.pkg.to_lowercase().clone())
-                            .or_default()
-                            .push(Package {
-                                family: package
-                                    .download_url
-                                    .split('/')
-                                    .rev()
-                                    .nth(1)
-                                    .map(|v| v.to_owned())
-                                    .filter(|v| v != ARCH),
-                                ..package.clone()
-                            });
-                        acc
-                    });
-
-                (key.clone(), package_map)
-            })
-            .collect();
+impl MetadataFetcher {
+    pub fn new() -> Self {
+        Self
+    }
 
-        let content = rmp_serde::to_vec(&metadata)
-            .context("Failed to serialize package metadata to MessagePack")?;
+    /// Fetches the metadata of the repository and writes it to the registry. The metadata is
+    /// verified against `checksum`, the content of its `.bsum` file, if given.
+    pub async fn execute(&self, repository: &Repository, checksum: Option<&[u8]>) -> Result<()> {
+        let parsed = self.fetch_metadata(repository, checksum).await?;
 
         let path = repository.get_path();
         if let Some(parent) = path.parent() {
@@ -78,28 +73,135 @@ impl MetadataFetcher {
                 .context("Failed to create registry directory")?;
         }
 
-        fs::write(&path, &content).await.with_context(|| {
+        // Build the database next to the live one and swap it in once it is complete, so a
+        // failed sync never leaves a partially written registry behind.
+        let temp_path = path.with_extension("db.part");
+        if temp_path.exists() {
+            fs::remove_file(&temp_path).await?;
+        }
+
+        write_metadata_db(&temp_path, repository, &parsed).with_context(|| {
             format!(
                 "Failed to write metadata for {}",
                 repository.name.clone().color(Color::Yellow)
             )
         })?;
 
+        fs::rename(&temp_path, &path).await.with_context(|| {
+            format!(
+                "Failed to write metadata for {}",
+                repository.name.clone().color(Color::Yellow)
+            )
+        })?;
+
+        // Registry from older versions stored as MessagePack
+        let legacy_path = REGISTRY_PATH.join(&repository.name);
+        if legacy_path.is_file() {
+            let _ = fs::remove_file(legacy_path).await;
+        }
+
         let _ = self.fetch_icons(repository).await;
 
-        Ok(content)
+        Ok(())
+    }
+
+    /// Downloads and parses the metadata of the repository, verifying its checksum and, if the
+    /// repository has a `pubkey`, its signature. The metadata is parsed while it is downloaded,
+    /// so it is never held in memory as a whole.
+    async fn fetch_metadata(
+        &self,
+        repository: &Repository,
+        checksum: Option<&[u8]>,
+    ) -> Result<RepositoryResponse> {
+        fetch_json(
+            repository,
+            &repository.metadata_file(),
+            "metadata",
+            false,
+            checksum,
+        )
+        .await
+    }
+
+    /// Updates the registry of the repository from the metadata with checksum `from` to the
+    /// one with checksum `to`, by applying the chain of deltas published by the repository.
+    /// Fails without touching the registry if the chain is incomplete.
+    pub async fn apply_deltas(
+        &self,
+        repository: &Repository,
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<()> {
+        let target = checksum_key(to).context("Invalid remote checksum")?;
+        let mut current = checksum_key(from).context("Invalid local checksum")?;
+
+        let mut deltas = Vec::new();
+        while current != target {
+            if deltas.len() == MAX_DELTA_CHAIN {
+                anyhow::bail!("Metadata delta chain is too long");
+            }
+            let delta = self.fetch_delta(repository, &current).await?;
+            current = delta.checksum.trim().to_owned();
+            deltas.push(delta);
+        }
+
+        let path = repository.get_path();
+        let temp_path = path.with_extension("db.part");
+        fs::copy(&path, &temp_path)
+            .await
+            .context("Failed to copy registry")?;
+
+        if let Err(e) = write_metadata_deltas(&temp_path, repository, &deltas) {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        fs::rename(&temp_path, &path)
+            .await
+            .context("Failed to write metadata")?;
+
+        Ok(())
+    }
+
+    /// Downloads the delta updating the metadata with checksum `checksum`.
+    async fn fetch_delta(&self, repository: &Repository, checksum: &str) -> Result<MetadataDelta> {
+        let file_name = format!("{}.delta/{}.json", repository.metadata_file(), checksum);
+        let (_, delta_checksum) = download_mirrored(
+            repository,
+            &format!("{file_name}.bsum"),
+            "metadata delta",
+            true,
+        )
+        .await?;
+        if blake3_checksum(&delta_checksum).is_none() {
+            anyhow::bail!("Invalid checksum for metadata delta {}", checksum);
+        }
+
+        fetch_json(
+            repository,
+            &file_name,
+            "metadata delta",
+            true,
+            Some(&delta_checksum),
+        )
+        .await
+    }
+
+    /// Checks that the repository serves valid metadata.
+    pub async fn validate(&self, repository: &Repository) -> Result<()> {
+        self.fetch_metadata(repository, None).await.map(|_| ())
     }
 
     async fn fetch_icon(
         &self,
+        repository: &Repository,
         icon_path: PathBuf,
-        base_url: String,
+        urls: Vec<String>,
         key: &str,
     ) -> Result<Option<(String, Vec<u8>)>> {
         if fs::metadata(&icon_path).await.is_ok() {
             Ok(None)
         } else {
-            let content = download(&base_url, "icon", true).await?;
+            let (_, content) = download_any(repository, urls, "icon", true).await?;
             Ok(Some((key.to_owned(), content))) // Return the key and icon data if downloaded
         }
     }
@@ -109,13 +211,17 @@ impl MetadataFetcher {
         let icon_futures: Vec<_> = repository
             .sources
             .iter()
-            .map(|(key, base_url)| {
-                let base_url = format!("{}/{}.default.png", base_url, key);
+            .map(|(key, mirrors)| {
+                let urls = mirrors
+                    .as_slice()
+                    .iter()
+                    .map(|base_url| format!("{}/{}.default.png", base_url, key))
+                    .collect();
 
                 let icon_path = REGISTRY_PATH
                     .join("icons")
                     .join(format!("{}-{}.png", repository.name, key));
-                self.fetch_icon(icon_path, base_url, key)
+                self.fetch_icon(repository, icon_path, urls, key)
             })
             .collect();
 
@@ -141,18 +247,783 @@ impl MetadataFetcher {
     }
 
     pub async fn checksum(&self, repository: &Repository) -> Result<Vec<u8>> {
-        let url = format!(
-            "{}/{}",
-            repository.url,
-            repository
-                .metadata
-                .to_owned()
-                .map(|file| format!("{file}.bsum"))
-                .unwrap_or("metadata.json.bsum".to_owned())
-        );
+        let metadata_file = repository.metadata_file();
+
+        // Local repositories don't have to publish a checksum, the metadata is cheap to hash.
+        if let Some(dir) = repository.local_path() {
+            if !dir.join(format!("{metadata_file}.bsum")).exists() {
+                let checksum = calculate_checksum(&dir.join(&metadata_file)).await?;
+                return Ok(checksum.into_bytes());
+            }
+        }
 
-        let content = download(&url, "metadata", true).await?;
+        let file_name = format!("{metadata_file}.bsum");
+        let (_, content) = download_mirrored(repository, &file_name, "metadata", true).await?;
 
         Ok(content)
     }
 }
+
+/// Downloads `file_name` from the mirrors of the repository, returning the URL it was fetched
+/// from along with its content.
+async fn download_mirrored(
+    repository: &Repository,
+    file_name: &str,
+    what: &str,
+    silent: bool,
+) -> Result<(String, Vec<u8>)> {
+    let urls = repository
+        .base_urls()
+        .into_iter()
+        .map(|base_url| format!("{}/{}", base_url, file_name))
+        .collect();
+    download_any(repository, urls, what, silent).await
+}
+
+/// Downloads the first of `urls` that can be fetched and remembers its mirror, returning the
+/// URL along with its content.
+async fn download_any(
+    repository: &Repository,
+    urls: Vec<String>,
+    what: &str,
+    silent: bool,
+) -> Result<(String, Vec<u8>)> {
+    let mut last_error = None;
+    for url in urls {
+        match download(&url, what, silent).await {
+            Ok(content) => {
+                repository.mark_mirror_working(&url);
+                return Ok((url, content));
+            }
+            Err(e) => {
+                debug!("{}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mirror to fetch {} from", what)))
+}
+
+/// Downloads `file_name` from the first mirror of the repository that serves it and parses it
+/// as JSON while it is downloaded, verifying it against `checksum`, the content of its `.bsum`
+/// file, and the signature of the repository.
+async fn fetch_json<T>(
+    repository: &Repository,
+    file_name: &str,
+    what: &str,
+    silent: bool,
+    checksum: Option<&[u8]>,
+) -> Result<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let checksum = checksum.and_then(blake3_checksum);
+    let mut last_error = None;
+    for base_url in repository.base_urls() {
+        let url = format!("{}/{}", base_url, file_name);
+        let result = fetch_json_from(
+            &url,
+            file_name,
+            what,
+            silent,
+            checksum.as_deref(),
+            repository.pubkey.as_deref(),
+        )
+        .await;
+        match result {
+            Ok(parsed) => {
+                repository.mark_mirror_working(&url);
+                return Ok(parsed);
+            }
+            Err(e) => {
+                debug!("{:#}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No mirror to fetch {} from", what)))
+}
+
+async fn fetch_json_from<T>(
+    url: &str,
+    file_name: &str,
+    what: &str,
+    silent: bool,
+    checksum: Option<&str>,
+    pubkey: Option<&str>,
+) -> Result<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let signature = match pubkey {
+        Some(pubkey) => Some(fetch_signature(url, pubkey).await?),
+        None => None,
+    };
+    let verifier = JsonVerifier {
+        url: url.to_owned(),
+        what: what.to_owned(),
+        file_name: file_name.to_owned(),
+        checksum: checksum.map(String::from),
+        signature,
+    };
+
+    if let Some(path) = file_url_path(url) {
+        let file = std::fs::File::open(&path).with_context(|| {
+            format!(
+                "Error reading {} from {}",
+                what.color(Color::Cyan),
+                path.to_string_lossy().color(Color::Blue)
+            )
+        })?;
+        return spawn_blocking(move || verifier.parse(None, file)).await?;
+    }
+
+    http::retry(url, || async {
+        let response = http::request(url).await?;
+        if !silent {
+            info!(
+                "Fetching {} from {} [{}]",
+                what.color(Color::Cyan),
+                url.color(Color::Blue),
+                format_bytes(response.content_length().unwrap_or_default())
+            );
+        }
+
+        let encoding = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|encoding| encoding.to_str().ok())
+            .map(|encoding| encoding.trim().to_ascii_lowercase());
+        let (sender, receiver) = mpsc::channel(16);
+        let verifier = verifier.clone();
+        let parser = spawn_blocking(move || {
+            verifier.parse(encoding.as_deref(), ChannelReader::new(receiver))
+        });
+
+        let mut transfer = Ok(());
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    // The parser is done early if the content is invalid.
+                    if sender.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    transfer = Err(e);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+
+        let parsed = parser.await?;
+        // An interrupted transfer makes the content invalid too, report it so it is retried.
+        transfer?;
+        parsed
+    })
+    .await
+    .map_err(|e| match e.downcast_ref::<StatusError>() {
+        Some(err) => anyhow::anyhow!(
+            "Error fetching {} from {} [{}]",
+            what.color(Color::Cyan),
+            url.color(Color::Blue),
+            err.status.color(Color::Red)
+        ),
+        None => e,
+    })
+}
+
+/// Checks a JSON file against its checksum and signature while it is parsed.
+#[derive(Clone)]
+struct JsonVerifier {
+    url: String,
+    what: String,
+    file_name: String,
+    checksum: Option<String>,
+    signature: Option<(PublicKey, Signature)>,
+}
+
+impl JsonVerifier {
+    /// Parses the file read from `reader`, after undoing the `Content-Encoding` it was sent
+    /// with. The checksum and signature cover the file as published, so they are checked
+    /// before a compressed file is decompressed.
+    fn parse<T: DeserializeOwned>(&self, encoding: Option<&str>, reader: impl Read) -> Result<T> {
+        let reader: Box<dyn Read> = match encoding {
+            None | Some("identity") => Box::new(reader),
+            Some("gzip") | Some("x-gzip") => Box::new(GzDecoder::new(reader)),
+            Some("zstd") => Box::new(zstd::Decoder::new(reader)?),
+            Some(encoding) => anyhow::bail!(
+                "Unsupported Content-Encoding {} for {}",
+                encoding.color(Color::Yellow),
+                self.url.clone().color(Color::Blue)
+            ),
+        };
+
+        let signature = match self.signature {
+            Some((ref pubkey, ref signature)) => Some(match pubkey.verify_stream(signature) {
+                Ok(verifier) => SignatureCheck::Stream(Box::new(verifier)),
+                // Signatures in the legacy format can only be checked against the whole file.
+                Err(minisign_verify::Error::UnsupportedLegacyMode) => {
+                    SignatureCheck::Legacy(Vec::new())
+                }
+                Err(e) => return Err(self.signature_error(e)),
+            }),
+            None => None,
+        };
+        let mut reader = VerifyingReader {
+            inner: reader,
+            hasher: blake3::Hasher::new(),
+            signature,
+        };
+
+        let parsed = decode_json(&self.file_name, &mut reader);
+        // The decoder may stop short of the end, the checksum and signature cover all of it.
+        let drained = io::copy(&mut reader, &mut io::sink());
+
+        if let Some(ref expected) = self.checksum {
+            let actual = reader.hasher.finalize().to_hex();
+            if !expected.eq_ignore_ascii_case(actual.as_str()) {
+                anyhow::bail!(
+                    "Checksum verification failed for {}. Expected {}, got {}",
+                    self.url.clone().color(Color::Blue),
+                    expected.clone().color(Color::Green),
+                    actual.as_str().color(Color::Red)
+                );
+            }
+        }
+        let parsed = parsed.with_context(|| {
+            format!(
+                "Failed to parse {} from {}",
+                self.what.clone().color(Color::Cyan),
+                self.url.clone().color(Color::Blue)
+            )
+        })?;
+        drained?;
+
+        match (reader.signature, self.signature.as_ref()) {
+            (Some(SignatureCheck::Stream(mut verifier)), _) => verifier.finalize(),
+            (Some(SignatureCheck::Legacy(content)), Some((pubkey, signature))) => {
+                pubkey.verify(&content, signature, true)
+            }
+            _ => Ok(()),
+        }
+        .map_err(|e| self.signature_error(e))?;
+
+        Ok(parsed)
+    }
+
+    fn signature_error(&self, error: minisign_verify::Error) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Metadata signature verification failed for {}: {}",
+            self.url.clone().color(Color::Blue),
+            error
+        )
+    }
+}
+
+enum SignatureCheck<'a> {
+    Stream(Box<StreamVerifier<'a>>),
+    Legacy(Vec<u8>),
+}
+
+/// Feeds everything read through it to the checksum and signature checks.
+struct VerifyingReader<'a, R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    signature: Option<SignatureCheck<'a>>,
+}
+
+impl<R: Read> Read for VerifyingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        match self.signature {
+            Some(SignatureCheck::Stream(ref mut verifier)) => verifier.update(&buf[..len]),
+            Some(SignatureCheck::Legacy(ref mut content)) => content.extend_from_slice(&buf[..len]),
+            None => {}
+        }
+        Ok(len)
+    }
+}
+
+/// Reads the chunks of a response body as they are received.
+struct ChannelReader<B> {
+    receiver: mpsc::Receiver<B>,
+    chunk: Option<B>,
+    position: usize,
+}
+
+impl<B> ChannelReader<B> {
+    fn new(receiver: mpsc::Receiver<B>) -> Self {
+        Self {
+            receiver,
+            chunk: None,
+            position: 0,
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Read for ChannelReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref chunk) = self.chunk {
+                let remaining = &chunk.as_ref()[self.position..];
+                if !remaining.is_empty() {
+                    let len = remaining.len().min(buf.len());
+                    buf[..len].copy_from_slice(&remaining[..len]);
+                    self.position += len;
+                    return Ok(len);
+                }
+            }
+
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = Some(chunk);
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Compression of a metadata file.
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression from the extension of the metadata file, falling back to the
+    /// magic bytes at the start of its content.
+    fn detect(file_name: &str, content: &[u8]) -> Self {
+        if file_name.ends_with(".zst") || content.starts_with(&ZSTD_MAGIC_BYTES) {
+            Compression::Zstd
+        } else if file_name.ends_with(".gz") || content.starts_with(&GZIP_MAGIC_BYTES) {
+            Compression::Gzip
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Parses JSON content of `file_name` from `reader`, decompressing it first if needed.
+fn decode_json<T: DeserializeOwned>(file_name: &str, reader: impl Read) -> Result<T> {
+    let mut reader = BufReader::new(reader);
+    let parsed = match Compression::detect(file_name, reader.fill_buf()?) {
+        Compression::Zstd => serde_json::from_reader(BufReader::new(zstd::Decoder::new(reader)?)),
+        Compression::Gzip => serde_json::from_reader(BufReader::new(GzDecoder::new(reader))),
+        Compression::None => serde_json::from_reader(reader),
+    };
+
+    Ok(parsed?)
+}
+
+/// Returns the checksum from the content of a `.bsum` file.
+pub fn checksum_key(checksum: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(checksum)
+        .split_whitespace()
+        .next()
+        .map(String::from)
+}
+
+/// Returns the family of a package, derived from the directory of its download URL.
+pub fn package_family(download_url: &str) -> &str {
+    download_url
+        .split('/')
+        .rev()
+        .nth(1)
+        .filter(|v| *v != ARCH)
+        .unwrap_or_default()
+}
+
+/// Returns the blake3 checksum from the content of a `.bsum` file. Checksum files in an
+/// unknown format are only used to detect changes and not verified.
+fn blake3_checksum(checksum: &[u8]) -> Option<String> {
+    checksum_key(checksum)
+        .filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Downloads the detached minisign signature of the file at `url`.
+async fn fetch_signature(url: &str, pubkey: &str) -> Result<(PublicKey, Signature)> {
+    let pubkey = PublicKey::from_base64(pubkey.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid repository pubkey: {}", e))?;
+
+    let signature_url = format!("{}.minisig", url);
+    let signature = download(&signature_url, "signature", true).await?;
+    let signature = String::from_utf8(signature)
+        .ok()
+        .and_then(|signature| Signature::decode(&signature).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid metadata signature at {}",
+                signature_url.color(Color::Blue)
+            )
+        })?;
+
+    Ok((pubkey, signature))
+}
+
+fn write_metadata_db(
+    path: &Path,
+    repository: &Repository,
+    metadata: &RepositoryResponse,
+) -> Result<()> {
+    let mut conn = soar_db::metadata::init_db(path)
+        .map_err(|e| anyhow::anyhow!("Failed to initialize metadata database: {}", e))?;
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO repository (name) VALUES (?1)",
+        params![repository.name],
+    )?;
+
+    let base_url = Url::parse(&format!("{}/", repository.base_url())).ok();
+
+    let mut families = HashMap::new();
+    let mut icons = HashMap::new();
+    for (collection, packages) in &metadata.collection {
+        tx.execute(
+            "INSERT INTO collections (name) VALUES (?1)",
+            params![collection],
+        )?;
+        let collection_id = tx.last_insert_rowid();
+
+        for package in packages {
+            insert_package(
+                &tx,
+                collection_id,
+                base_url.as_ref(),
+                package,
+                &mut families,
+                &mut icons,
+            )?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn write_metadata_deltas(
+    path: &Path,
+    repository: &Repository,
+    deltas: &[MetadataDelta],
+) -> Result<()> {
+    let mut conn = soar_db::metadata::init_db(path)
+        .map_err(|e| anyhow::anyhow!("Failed to initialize metadata database: {}", e))?;
+
+    let tx = conn.transaction()?;
+    let base_url = Url::parse(&format!("{}/", repository.base_url())).ok();
+
+    let mut families = HashMap::new();
+    {
+        let mut stmt = tx.prepare("SELECT name, id FROM families")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (name, id) = row?;
+            families.entry(name).or_insert(id);
+        }
+    }
+    let mut icons = HashMap::new();
+    {
+        let mut stmt = tx.prepare("SELECT url, id FROM icons")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for row in rows {
+            let (url, id) = row?;
+            icons.insert(url, id);
+        }
+    }
+
+    for delta in deltas {
+        // A delta only applies to the metadata it was made from. Anything else means the local
+        // registry differs from it, and is rebuilt from the full metadata instead.
+        let existing = delta.removed.iter().chain(&delta.changed);
+        for (collection, packages) in existing {
+            for package in packages {
+                if !delete_package(&tx, collection, package)? {
+                    anyhow::bail!(
+                        "Metadata delta doesn't apply, {} is missing",
+                        package.full_name('/').color(Color::Blue)
+                    );
+                }
+            }
+        }
+        for (collection, packages) in &delta.added {
+            for package in packages {
+                if delete_package(&tx, collection, package)? {
+                    anyhow::bail!(
+                        "Metadata delta doesn't apply, {} already exists",
+                        package.full_name('/').color(Color::Blue)
+                    );
+                }
+            }
+        }
+
+        for (collection, packages) in delta.changed.iter().chain(&delta.added) {
+            tx.execute(
+                "INSERT OR IGNORE INTO collections (name) VALUES (?1)",
+                params![collection],
+            )?;
+            let collection_id = tx.query_row(
+                "SELECT id FROM collections WHERE name = ?1",
+                params![collection],
+                |row| row.get(0),
+            )?;
+
+            for package in packages {
+                insert_package(
+                    &tx,
+                    collection_id,
+                    base_url.as_ref(),
+                    package,
+                    &mut families,
+                    &mut icons,
+                )?;
+            }
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Deletes the package from the registry, returning whether it was there.
+fn delete_package(tx: &Transaction, collection: &str, package: &Package) -> Result<bool> {
+    let id: Option<i64> = tx
+        .query_row(
+            "SELECT p.id FROM packages p
+            JOIN collections c ON c.id = p.collection_id
+            JOIN families f ON f.id = p.family_id
+            WHERE c.name = ?1 AND f.name = ?2 AND p.pkg = ?3 AND p.pkg_name = ?4",
+            params![
+                collection,
+                package_family(&package.download_url),
+                package.pkg,
+                package.pkg_name
+            ],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    if let Some(id) = id {
+        for table in ["homepages", "notes", "source_urls", "provides"] {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE package_id = ?1"),
+                params![id],
+            )?;
+        }
+        tx.execute("DELETE FROM packages WHERE id = ?1", params![id])?;
+    }
+
+    Ok(id.is_some())
+}
+
+fn get_or_insert_family(
+    tx: &Transaction,
+    families: &mut HashMap<String, i64>,
+    name: &str,
+) -> Result<i64> {
+    if let Some(id) = families.get(name) {
+        return Ok(*id);
+    }
+    tx.execute("INSERT INTO families (name) VALUES (?1)", params![name])?;
+    let id = tx.last_insert_rowid();
+    families.insert(name.to_owned(), id);
+    Ok(id)
+}
+
+/// Inserts a package into the registry. Relative download URLs are resolved against
+/// `base_url`, the URL of the repository. Packages pointing to local files are skipped unless
+/// the repository is local itself.
+fn insert_package(
+    tx: &Transaction,
+    collection_id: i64,
+    base_url: Option<&Url>,
+    package: &Package,
+    families: &mut HashMap<String, i64>,
+    icons: &mut HashMap<String, i64>,
+) -> Result<()> {
+    let family_id = get_or_insert_family(tx, families, package_family(&package.download_url))?;
+    let download_url = match base_url {
+        Some(base_url) if Url::parse(&package.download_url).is_err() => base_url
+            .join(&package.download_url)
+            .map(String::from)
+            .unwrap_or_else(|_| package.download_url.clone()),
+        _ => package.download_url.clone(),
+    };
+    let is_local_repository = base_url.is_some_and(|base_url| base_url.scheme() == "file");
+    let is_local_file = Url::parse(&download_url).is_ok_and(|url| url.scheme() == "file");
+    if is_local_file && !is_local_repository {
+        warn!(
+            "Skipping {} as it points to a local file {}",
+            package.full_name('/').color(Color::Blue),
+            download_url.color(Color::Blue)
+        );
+        return Ok(());
+    }
+
+    let icon_id = match icons.get(&package.icon) {
+        Some(id) => *id,
+        None => {
+            tx.execute("INSERT INTO icons (url) VALUES (?1)", params![package.icon])?;
+            let id = tx.last_insert_rowid();
+            icons.insert(package.icon.clone(), id);
+            id
+        }
+    };
+
+    let inserted = tx.execute(
+        "INSERT OR IGNORE INTO packages (
+            collection_id, family_id, icon_id, pkg, pkg_id, pkg_name, description, version,
+            download_url, size, checksum, build_date, build_script, build_log, category, desktop
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            collection_id,
+            family_id,
+            icon_id,
+            package.pkg,
+            package.pkg_id,
+            package.pkg_name,
+            package.description,
+            package.version,
+            download_url,
+            parse_size(&package.size).unwrap_or_default() as i64,
+            package.bsum,
+            package.build_date,
+            package.build_script,
+            package.build_log,
+            package.category,
+            package.desktop,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(());
+    }
+    let package_id = tx.last_insert_rowid();
+
+    if !package.homepage.is_empty() {
+        tx.execute(
+            "INSERT OR IGNORE INTO homepages (url, package_id) VALUES (?1, ?2)",
+            params![package.homepage, package_id],
+        )?;
+    }
+    if !package.src_url.is_empty() {
+        tx.execute(
+            "INSERT OR IGNORE INTO source_urls (url, package_id) VALUES (?1, ?2)",
+            params![package.src_url, package_id],
+        )?;
+    }
+    if !package.note.is_empty() {
+        tx.execute(
+            "INSERT OR IGNORE INTO notes (note, package_id) VALUES (?1, ?2)",
+            params![package.note, package_id],
+        )?;
+    }
+    for provide in package.provides.split(',').map(str::trim) {
+        if provide.is_empty() {
+            continue;
+        }
+        let provide_id = get_or_insert_family(tx, families, provide)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO provides (family_id, package_id) VALUES (?1, ?2)",
+            params![provide_id, package_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression as GzCompression};
+
+    use super::*;
+
+    fn verifier(file_name: &str, checksum: &[u8]) -> JsonVerifier {
+        JsonVerifier {
+            url: format!("https://example.com/{}", file_name),
+            what: "metadata".to_owned(),
+            file_name: file_name.to_owned(),
+            checksum: Some(blake3::hash(checksum).to_hex().to_string()),
+            signature: None,
+        }
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_checks_compressed_metadata_as_published() {
+        let published = zstd::encode_all(&br#"{"bin": []}"#[..], 0).unwrap();
+        let verifier = verifier("metadata.json.zst", &published);
+
+        let parsed: HashMap<String, Vec<Package>> =
+            verifier.parse(None, published.as_slice()).unwrap();
+        assert!(parsed["bin"].is_empty());
+
+        // Content-Encoding only applies to the transfer, not to what the checksum covers.
+        let parsed: HashMap<String, Vec<Package>> = verifier
+            .parse(Some("gzip"), gzip(&published).as_slice())
+            .unwrap();
+        assert!(parsed["bin"].is_empty());
+
+        let tampered = zstd::encode_all(&br#"{"bin": [] }"#[..], 0).unwrap();
+        assert!(verifier
+            .parse::<HashMap<String, Vec<Package>>>(None, tampered.as_slice())
+            .is_err());
+    }
+
+    #[test]
+    fn insert_package_only_accepts_local_files_from_local_repositories() {
+        let mut conn = soar_db::metadata::init_db(":memory:").unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute("INSERT INTO collections (name) VALUES ('bin')", [])
+            .unwrap();
+        let collection_id = tx.last_insert_rowid();
+        let package = Package {
+            pkg: "hello".to_owned(),
+            pkg_name: "hello".to_owned(),
+            download_url: "file:///usr/bin/hello".to_owned(),
+            ..Default::default()
+        };
+        let count = |tx: &Transaction| -> i64 {
+            tx.query_row("SELECT COUNT(*) FROM packages", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let remote = Url::parse("https://example.com/repo/").unwrap();
+        insert_package(
+            &tx,
+            collection_id,
+            Some(&remote),
+            &package,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(count(&tx), 0);
+
+        let local = Url::parse("file:///srv/repo/").unwrap();
+        insert_package(
+            &tx,
+            collection_id,
+            Some(&local),
+            &package,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(count(&tx), 1);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::{
+    core::{
+        color::{Color, ColorExt},
+        file::{get_file_type, FileType},
+        util::{calculate_checksum, format_bytes},
+    },
+    package::Package,
+};
+
+use super::fetcher::{checksum_key, package_family, MetadataDelta};
+
+/// Packages of a repository by collection, as published in its metadata.
+type Metadata = BTreeMap<String, Vec<Package>>;
+
+/// Writes repository metadata for the packages in `dir`, along with its blake3 checksum.
+///
+/// Every ELF, AppImage and FlatImage file directly inside `dir` becomes a package of
+/// `collection`. Fields of a package can be set in a `<file>.json` sidecar next to it, which
+/// takes precedence over the generated values. If `dir` already has metadata, a delta from it
+/// to the new metadata is written as well.
+pub async fn build_index(dir: &Path, output: &str, collection: &str) -> Result<()> {
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(dir).await.with_context(|| {
+        format!(
+            "Failed to read directory {}",
+            dir.to_string_lossy().color(Color::Blue)
+        )
+    })?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.is_file() && is_package_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut packages = Vec::new();
+    for path in files {
+        let package = index_package(&path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to index {}: {:#}",
+                path.to_string_lossy().color(Color::Blue),
+                e
+            )
+        })?;
+        info!(
+            "Indexed {} [{}]",
+            package.pkg.clone().color(Color::Blue),
+            package.version.clone().color(Color::Green)
+        );
+        packages.push(package);
+    }
+
+    if packages.is_empty() {
+        warn!(
+            "No packages found in {}",
+            dir.to_string_lossy().color(Color::Blue)
+        );
+    }
+
+    let total = packages.len();
+    let metadata = BTreeMap::from([(collection.to_owned(), packages)]);
+    let content = serde_json::to_vec_pretty(&metadata)?;
+
+    let metadata_path = dir.join(output);
+    let checksum_path = dir.join(format!("{output}.bsum"));
+    let previous = read_previous_metadata(&metadata_path, &checksum_path).await;
+
+    fs::write(&metadata_path, &content).await.with_context(|| {
+        format!(
+            "Failed to write {}",
+            metadata_path.to_string_lossy().color(Color::Blue)
+        )
+    })?;
+
+    let checksum = calculate_checksum(&metadata_path).await?;
+    fs::write(&checksum_path, &checksum)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to write {}",
+                checksum_path.to_string_lossy().color(Color::Blue)
+            )
+        })?;
+
+    if let Some((previous_checksum, previous_metadata)) = previous {
+        if previous_checksum != checksum {
+            let delta = diff_metadata(&previous_metadata, &metadata, checksum);
+            let delta_path = dir
+                .join(format!("{output}.delta"))
+                .join(format!("{previous_checksum}.json"));
+            write_delta(&delta_path, &delta).await.with_context(|| {
+                format!(
+                    "Failed to write {}",
+                    delta_path.to_string_lossy().color(Color::Blue)
+                )
+            })?;
+        }
+    }
+
+    info!(
+        "Wrote {} packages to {}",
+        total.color(Color::BrightMagenta),
+        metadata_path.to_string_lossy().color(Color::Blue)
+    );
+
+    Ok(())
+}
+
+/// Reads the metadata written by a previous run, along with its checksum.
+async fn read_previous_metadata(
+    metadata_path: &Path,
+    checksum_path: &Path,
+) -> Option<(String, Metadata)> {
+    let checksum = checksum_key(&fs::read(checksum_path).await.ok()?)?;
+    let metadata = serde_json::from_slice(&fs::read(metadata_path).await.ok()?).ok()?;
+    Some((checksum, metadata))
+}
+
+/// Returns the changes from `previous` to `current`, whose checksum is `checksum`.
+fn diff_metadata(previous: &Metadata, current: &Metadata, checksum: String) -> MetadataDelta {
+    let key = |package: &Package| {
+        (
+            package_family(&package.download_url).to_owned(),
+            package.pkg.clone(),
+            package.pkg_name.clone(),
+        )
+    };
+
+    let mut delta = MetadataDelta {
+        checksum,
+        ..Default::default()
+    };
+    for (collection, packages) in current {
+        let previous_packages = previous
+            .get(collection)
+            .map(|packages| {
+                packages
+                    .iter()
+                    .map(|p| (key(p), p))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        for package in packages {
+            let entry = match previous_packages.get(&key(package)) {
+                None => &mut delta.added,
+                Some(previous_package)
+                    if serde_json::to_value(previous_package).ok()
+                        != serde_json::to_value(package).ok() =>
+                {
+                    &mut delta.changed
+                }
+                Some(_) => continue,
+            };
+            entry
+                .entry(collection.clone())
+                .or_default()
+                .push(package.clone());
+        }
+    }
+    for (collection, packages) in previous {
+        let current_keys = current
+            .get(collection)
+            .map(|packages| packages.iter().map(key).collect::<HashSet<_>>())
+            .unwrap_or_default();
+
+        for package in packages {
+            if !current_keys.contains(&key(package)) {
+                delta
+                    .removed
+                    .entry(collection.clone())
+                    .or_default()
+                    .push(package.clone());
+            }
+        }
+    }
+
+    delta
+}
+
+/// Writes the delta to `path`, along with its blake3 checksum.
+async fn write_delta(path: &Path, delta: &MetadataDelta) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(delta)?).await?;
+
+    let mut checksum_path = path.as_os_str().to_owned();
+    checksum_path.push(".bsum");
+    fs::write(checksum_path, calculate_checksum(path).await?).await?;
+
+    info!(
+        "Wrote metadata delta to {}",
+        path.to_string_lossy().color(Color::Blue)
+    );
+
+    Ok(())
+}
+
+fn is_package_file(path: &Path) -> bool {
+    File::open(path)
+        .map(|file| get_file_type(&mut BufReader::new(file)) != FileType::Unknown)
+        .unwrap_or(false)
+}
+
+async fn index_package(path: &Path) -> Result<Package> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let pkg = ["AppImage", "FlatImage"]
+        .iter()
+        .find_map(|ext| file_name.strip_suffix(&format!(".{ext}")))
+        .unwrap_or(&file_name)
+        .to_owned();
+
+    let meta = fs::metadata(path).await?;
+    let build_date = meta
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .map(|modified| modified.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default();
+
+    let package = Package {
+        pkg,
+        pkg_name: file_name.clone(),
+        version: "latest".to_owned(),
+        download_url: file_name,
+        size: format_bytes(meta.len()),
+        bsum: calculate_checksum(path).await?,
+        build_date,
+        ..Default::default()
+    };
+
+    let sidecar_path = PathBuf::from(format!("{}.json", path.display()));
+    if !sidecar_path.is_file() {
+        return Ok(package);
+    }
+
+    let sidecar: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&fs::read(&sidecar_path).await?)
+            .context("Invalid sidecar metadata")?;
+    let mut value = serde_json::to_value(package)?;
+    if let Some(fields) = value.as_object_mut() {
+        fields.extend(sidecar);
+    }
+
+    serde_json::from_value(value).context("Invalid sidecar metadata")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(family: &str, pkg: &str, version: &str) -> Package {
+        Package {
+            pkg: pkg.to_owned(),
+            pkg_name: pkg.to_owned(),
+            version: version.to_owned(),
+            download_url: format!("https://example.com/{}/{}", family, pkg),
+            ..Default::default()
+        }
+    }
+
+    fn names(packages: &BTreeMap<String, Vec<Package>>) -> Vec<(String, String, String)> {
+        packages
+            .iter()
+            .flat_map(|(collection, packages)| {
+                packages.iter().map(|p| {
+                    let name = format!("{}/{}", package_family(&p.download_url), p.pkg);
+                    (collection.clone(), name, p.version.clone())
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_metadata_finds_added_changed_and_removed_packages() {
+        let previous = BTreeMap::from([(
+            "bin".to_owned(),
+            vec![
+                package("a", "same", "1.0"),
+                package("a", "updated", "1.0"),
+                package("a", "dropped", "1.0"),
+            ],
+        )]);
+        let current = BTreeMap::from([
+            (
+                "bin".to_owned(),
+                vec![
+                    package("a", "same", "1.0"),
+                    package("a", "updated", "2.0"),
+                    // Packages are told apart by family as well.
+                    package("b", "dropped", "1.0"),
+                ],
+            ),
+            ("pkg".to_owned(), vec![package("a", "new", "1.0")]),
+        ]);
+
+        let delta = diff_metadata(&previous, &current, "checksum".to_owned());
+
+        assert_eq!(delta.checksum, "checksum");
+        assert_eq!(
+            names(&delta.added),
+            [
+                ("bin".to_owned(), "b/dropped".to_owned(), "1.0".to_owned()),
+                ("pkg".to_owned(), "a/new".to_owned(), "1.0".to_owned()),
+            ]
+        );
+        assert_eq!(
+            names(&delta.changed),
+            [("bin".to_owned(), "a/updated".to_owned(), "2.0".to_owned())]
+        );
+        assert_eq!(
+            names(&delta.removed),
+            [("bin".to_owned(), "a/dropped".to_owned(), "1.0".to_owned())]
+        );
+    }
+
+    #[test]
+    fn diff_metadata_of_identical_metadata_is_empty() {
+        let metadata = BTreeMap::from([("bin".to_owned(), vec![package("a", "same", "1.0")])]);
+
+        let delta = diff_metadata(&metadata, &metadata, "checksum".to_owned());
+
+        assert!(delta.added.is_empty());
+        assert!(delta.changed.is_empty());
+        assert!(delta.removed.is_empty());
+    }
+}
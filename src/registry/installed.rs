@@ -1,24 +1,36 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use rusqlite::{params, Connection, Row, Transaction};
+use serde::Deserialize;
 use tokio::fs;
-use tracing::info;
+use tracing::{error, info, warn};
 
 use crate::{
     core::{
         color::{Color, ColorExt},
-        constant::{BIN_PATH, INSTALL_TRACK_PATH, PACKAGES_PATH},
+        config::CONFIG,
+        constant::{BIN_PATH, CORE_DB_PATH, INSTALL_TRACK_PATH, PACKAGES_PATH},
         util::{format_bytes, parse_size},
     },
-    package::{parse_package_query, remove::Remover, ResolvedPackage},
+    package::{
+        appimage::{move_portable_dirs, reapply_portable_dir, remove_applinks, restore_applinks},
+        parse_package_query,
+        remove::Remover,
+        sandbox::{bin_target, create_bin_link, is_stale_wrapper, SandboxRules},
+        Package, ResolvedPackage,
+    },
 };
 
 use super::storage::PackageStorage;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone)]
 pub struct InstalledPackage {
+    pub id: i64,
     pub repo_name: String,
     pub collection: String,
     pub name: String,
@@ -28,36 +40,164 @@ pub struct InstalledPackage {
     pub checksum: String,
     pub size: u64,
     pub timestamp: DateTime<Utc>,
+    pub pinned: bool,
+    pub disabled: bool,
+}
+
+/// Previous version of an installed package, kept around for rollback.
+#[derive(Debug, Clone)]
+pub struct PackageVersion {
+    pub id: i64,
+    pub version: String,
+    pub checksum: String,
+    pub size: u64,
+    pub installed_path: PathBuf,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Installed package as tracked in the legacy `installs/latest` MessagePack file.
+#[derive(Deserialize)]
+struct LegacyInstalledPackage {
+    repo_name: String,
+    collection: String,
+    name: String,
+    family: Option<String>,
+    bin_name: String,
+    version: String,
+    checksum: String,
+    size: u64,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct LegacyInstalledPackages {
+    packages: Vec<LegacyInstalledPackage>,
+}
+
+/// Portable directory settings an AppImage or FlatImage was installed with. An empty path
+/// creates the directory next to the package.
+#[derive(Debug, Clone, Default)]
+pub struct PortableOptions {
+    pub portable: Option<String>,
+    pub portable_home: Option<String>,
+    pub portable_config: Option<String>,
+}
+
+impl PortableOptions {
+    pub fn is_empty(&self) -> bool {
+        self.portable.is_none() && self.portable_home.is_none() && self.portable_config.is_none()
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug)]
 pub struct InstalledPackages {
     pub packages: Vec<InstalledPackage>,
+    conn: Connection,
 }
 
+const PACKAGE_COLUMNS: &str = "id, repo_name, collection, family, pkg, pkg_name, version, \
+                               checksum, size, installed_date, pinned, disabled";
+
 impl InstalledPackages {
     pub async fn new() -> Result<Self> {
-        let path = INSTALL_TRACK_PATH.join("latest");
-
-        if let Some(parent) = path.parent() {
+        if let Some(parent) = CORE_DB_PATH.parent() {
             fs::create_dir_all(parent)
                 .await
-                .context("Failed to create installs directory to track installations.")?;
+                .context("Failed to create database directory to track installations.")?;
         }
 
-        let packages = if path.exists() {
-            let content = tokio::fs::read(&path)
-                .await
-                .context("Failed to read installed packages")?;
+        let conn = soar_db::core::init_db(&*CORE_DB_PATH).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to initialize database {}: {}",
+                CORE_DB_PATH.to_string_lossy().color(Color::Blue),
+                e
+            )
+        })?;
 
-            let mut de = rmp_serde::Deserializer::new(&content[..]);
+        let mut installed_packages = InstalledPackages {
+            packages: Vec::new(),
+            conn,
+        };
+        installed_packages.migrate_legacy().await?;
+        installed_packages.packages = installed_packages.load()?;
 
-            InstalledPackages::deserialize(&mut de)?
-        } else {
-            InstalledPackages {
-                packages: Vec::new(),
+        Ok(installed_packages)
+    }
+
+    /// Regenerates the sandbox wrappers that launch another soar binary than the running one.
+    pub async fn refresh_sandbox_wrappers(&self) -> Result<()> {
+        for package in &self.packages {
+            let install_path = package.get_install_path();
+            let bin_path = BIN_PATH.join(&package.bin_name);
+            if package.disabled
+                || bin_target(&bin_path).as_ref() != Some(&install_path)
+                || !is_stale_wrapper(&bin_path)
+            {
+                continue;
             }
-        };
+
+            let sandbox = self.get_sandbox_rules(package.id, &package.repo_name)?;
+            link_bin(&install_path, &bin_path, sandbox.as_ref()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Imports packages tracked in the legacy `installs/latest` file into the database.
+    /// The legacy file is kept as `latest.bak` once it has been imported.
+    async fn migrate_legacy(&mut self) -> Result<()> {
+        let path = INSTALL_TRACK_PATH.join("latest");
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read(&path)
+            .await
+            .context("Failed to read installed packages")?;
+        let mut de = rmp_serde::Deserializer::new(&content[..]);
+        let legacy = LegacyInstalledPackages::deserialize(&mut de)
+            .context("Failed to parse legacy installed packages")?;
+
+        self.packages = legacy
+            .packages
+            .into_iter()
+            .map(|legacy_package| InstalledPackage {
+                id: 0,
+                repo_name: legacy_package.repo_name,
+                collection: legacy_package.collection,
+                name: legacy_package.name,
+                family: legacy_package.family,
+                bin_name: legacy_package.bin_name,
+                version: legacy_package.version,
+                checksum: legacy_package.checksum,
+                size: legacy_package.size,
+                timestamp: legacy_package.timestamp,
+                pinned: false,
+                disabled: false,
+            })
+            .collect();
+        self.save().await?;
+
+        fs::rename(&path, path.with_extension("bak"))
+            .await
+            .context("Failed to archive legacy installed packages")?;
+
+        info!(
+            "Migrated installed packages to {}",
+            CORE_DB_PATH.to_string_lossy().color(Color::Blue)
+        );
+
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<InstalledPackage>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM packages", PACKAGE_COLUMNS))?;
+        let packages = stmt
+            .query_map([], map_installed_package)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to read installed packages")?;
 
         Ok(packages)
     }
@@ -82,23 +222,63 @@ impl InstalledPackages {
         })
     }
 
-    pub async fn register_package(
+    /// Records the package as installed along with the portable settings it was installed
+    /// with, in a single transaction. Returns the install paths of versions that no longer
+    /// fit in the history, to be removed with [`remove_versions`] once the install is kept.
+    pub fn register_package(
         &mut self,
         resolved_package: &ResolvedPackage,
         checksum: &str,
-    ) -> Result<()> {
+        portable: &PortableOptions,
+    ) -> Result<Vec<PathBuf>> {
         let package = resolved_package.package.to_owned();
 
-        let new_installed = InstalledPackage {
+        let mut new_installed = InstalledPackage {
+            id: 0,
             repo_name: resolved_package.repo_name.to_owned(),
             collection: resolved_package.collection.to_string().to_owned(),
-            name: package.pkg,
-            family: package.family,
-            bin_name: package.pkg_name,
-            version: package.version,
+            name: package.pkg.clone(),
+            family: package.family.clone(),
+            bin_name: package.pkg_name.clone(),
+            version: package.version.clone(),
             checksum: checksum.to_owned(),
             size: parse_size(&package.size).unwrap_or_default(),
             timestamp: Utc::now(),
+            pinned: false,
+            disabled: false,
+        };
+
+        let existing = self.find_package_mut(resolved_package).cloned();
+
+        let pruned = {
+            let tx = self.conn.transaction()?;
+            let mut pruned = Vec::new();
+            new_installed.id = match existing {
+                Some(previous) => {
+                    new_installed.pinned = previous.pinned;
+                    new_installed.disabled = previous.disabled;
+                    update_package(&tx, previous.id, &new_installed, Some(&package))?;
+
+                    // Versions are kept by checksum, reinstalling one drops it from the history.
+                    tx.execute(
+                        "DELETE FROM package_versions WHERE package_id = ?1 AND checksum = ?2",
+                        params![previous.id, new_installed.checksum],
+                    )?;
+                    if previous.checksum != new_installed.checksum {
+                        insert_version(&tx, &previous)?;
+                    }
+                    pruned = prune_versions(&tx, previous.id, CONFIG.keep_versions.unwrap_or(1))?;
+
+                    previous.id
+                }
+                None => insert_package(&tx, &new_installed, Some(&package))?,
+            };
+            if !portable.is_empty() {
+                save_portable_options(&tx, new_installed.id, portable)?;
+            }
+            tx.commit()
+                .context("Failed to register package in database")?;
+            pruned
         };
 
         if let Some(installed) = self.find_package_mut(resolved_package) {
@@ -107,16 +287,512 @@ impl InstalledPackages {
             self.packages.push(new_installed);
         }
 
-        self.save().await?;
+        Ok(pruned)
+    }
+
+    pub async fn unregister_package(&mut self, installed_package: &InstalledPackage) -> Result<()> {
+        let versions = {
+            let tx = self.conn.transaction()?;
+            let versions = prune_versions(&tx, installed_package.id, 0)?;
+            tx.execute(
+                "DELETE FROM sandbox_rules WHERE package_id = ?1",
+                params![installed_package.id],
+            )?;
+            tx.execute(
+                "DELETE FROM sandbox_paths WHERE package_id = ?1",
+                params![installed_package.id],
+            )?;
+            tx.execute(
+                "DELETE FROM portable_package WHERE package_id = ?1",
+                params![installed_package.id],
+            )?;
+            tx.execute(
+                "DELETE FROM packages WHERE id = ?1",
+                params![installed_package.id],
+            )?;
+            tx.commit()
+                .context("Failed to unregister package from database")?;
+            versions
+        };
+
+        self.packages
+            .retain(|installed| installed.id != installed_package.id);
+
+        remove_versions(&versions).await;
 
         Ok(())
     }
 
-    pub async fn unregister_package(&mut self, installed_package: &InstalledPackage) -> Result<()> {
+    /// Returns the installed packages matching `package_name`. Packages from other
+    /// families are matched as well unless `exact` is set.
+    pub fn find_matching(&self, package_name: &str, exact: bool) -> Vec<InstalledPackage> {
+        let query = parse_package_query(package_name);
+
         self.packages
-            .retain(|installed| installed.full_name('-') != installed_package.full_name('-'));
+            .iter()
+            .filter(|package| {
+                if package.name != query.name || !query.matches_repo(&package.repo_name) {
+                    return false;
+                }
+                if let Some(ref ckey) = query.collection {
+                    if package.collection != *ckey {
+                        return false;
+                    }
+                }
 
-        self.save().await?;
+                match (&query.family, &package.family) {
+                    (None, None) => true,
+                    (None, Some(_)) => !exact,
+                    (Some(ref query_family), Some(ref package_family)) => {
+                        query_family == package_family
+                    }
+                    _ => false,
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the installed packages matching the names. Works from the installed packages
+    /// alone, so packages can be removed while repositories are unreachable.
+    pub async fn remove_packages(&mut self, package_names: &[String], exact: bool) -> Result<()> {
+        let mut packages_to_remove = Vec::new();
+        for package_name in package_names {
+            let matching_packages = self.find_matching(package_name, exact);
+            if matching_packages.is_empty() {
+                error!("{} is not installed.", package_name);
+            } else {
+                packages_to_remove.extend(matching_packages);
+            }
+        }
+
+        for package in packages_to_remove {
+            self.remove(&package).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn pin_packages(&mut self, package_names: &[String], pinned: bool) -> Result<()> {
+        for package_name in package_names {
+            let matching_packages = self.find_matching(package_name, false);
+            if matching_packages.is_empty() {
+                error!("{} is not installed.", package_name);
+                continue;
+            }
+
+            for package in matching_packages {
+                self.set_pinned(&package, pinned).await?;
+                info!(
+                    "{} is {}",
+                    package.full_name('/').color(Color::Blue),
+                    if pinned { "pinned" } else { "unpinned" }
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_pinned(
+        &mut self,
+        installed_package: &InstalledPackage,
+        pinned: bool,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "UPDATE packages SET pinned = ?1 WHERE id = ?2",
+                params![pinned, installed_package.id],
+            )
+            .context("Failed to update package in database")?;
+
+        if let Some(installed) = self
+            .packages
+            .iter_mut()
+            .find(|installed| installed.id == installed_package.id)
+        {
+            installed.pinned = pinned;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the previous versions kept for the package, newest first.
+    pub fn get_versions(&self, id: i64) -> Result<Vec<PackageVersion>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, version, checksum, size, installed_path, installed_date
+            FROM package_versions WHERE package_id = ?1 ORDER BY id DESC",
+        )?;
+        let versions = stmt
+            .query_map(params![id], |row| {
+                let size: i64 = row.get("size")?;
+                let installed_path: String = row.get("installed_path")?;
+                let installed_date: String = row.get("installed_date")?;
+
+                Ok(PackageVersion {
+                    id: row.get("id")?,
+                    version: row.get("version")?,
+                    checksum: row.get("checksum")?,
+                    size: size as u64,
+                    installed_path: PathBuf::from(installed_path),
+                    timestamp: DateTime::parse_from_rfc3339(&installed_date)
+                        .map(|date| date.with_timezone(&Utc))
+                        .unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(versions)
+    }
+
+    /// Switches the package back to a previously installed version, the latest one unless
+    /// `version` is given. The current version is kept in its place.
+    pub async fn rollback_package(
+        &mut self,
+        package_name: &str,
+        version: Option<&str>,
+    ) -> Result<()> {
+        let package = match self.find_matching(package_name, false).as_slice() {
+            [] => anyhow::bail!("{} is not installed.", package_name.color(Color::Blue)),
+            [package] => package.clone(),
+            _ => anyhow::bail!(
+                "Multiple installed packages match {}. Specify the family as well.",
+                package_name.color(Color::Blue)
+            ),
+        };
+
+        let versions = self.get_versions(package.id)?;
+        let target = match version {
+            Some(version) => versions.iter().find(|v| v.version == version),
+            None => versions.first(),
+        };
+        let Some(target) = target else {
+            if versions.is_empty() {
+                anyhow::bail!(
+                    "No previous versions of {} are kept.",
+                    package.full_name('/').color(Color::Blue)
+                );
+            }
+            let available = versions
+                .iter()
+                .map(|v| v.version.clone())
+                .collect::<Vec<_>>();
+            anyhow::bail!(
+                "Version {} of {} is not kept. Available versions: {}",
+                version.unwrap_or_default().color(Color::Yellow),
+                package.full_name('/').color(Color::Blue),
+                available.join(", ").color(Color::Yellow)
+            );
+        };
+
+        if !target.installed_path.exists() {
+            anyhow::bail!(
+                "Files of {} {} are missing at {}",
+                package.full_name('/').color(Color::Blue),
+                (&target.version).color(Color::Yellow),
+                target.installed_path.display().color(Color::Blue)
+            );
+        }
+
+        let current_path = package.get_install_path();
+        let rolled_back = InstalledPackage {
+            version: target.version.clone(),
+            checksum: target.checksum.clone(),
+            size: target.size,
+            timestamp: target.timestamp,
+            ..package.clone()
+        };
+
+        // Switch the files over first and record the rollback last, putting the files back if
+        // either step fails so the database always matches what is linked.
+        let sandbox = self.get_sandbox_rules(package.id, &package.repo_name)?;
+        let switched = switch_version(
+            &package,
+            sandbox.as_ref(),
+            &current_path,
+            &target.installed_path,
+        )
+        .await
+        .and_then(|_| {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                "DELETE FROM package_versions WHERE id = ?1",
+                params![target.id],
+            )?;
+            insert_version(&tx, &package)?;
+            update_package(&tx, package.id, &rolled_back, None)?;
+            tx.commit().context("Failed to update package in database")
+        });
+        if let Err(e) = switched {
+            if let Err(restore_error) = switch_version(
+                &package,
+                sandbox.as_ref(),
+                &target.installed_path,
+                &current_path,
+            )
+            .await
+            {
+                error!(
+                    "Failed to restore {} {}: {:#}",
+                    package.full_name('/').color(Color::Blue),
+                    (&package.version).color(Color::Yellow),
+                    restore_error
+                );
+            }
+            return Err(e);
+        }
+
+        if let Some(installed) = self
+            .packages
+            .iter_mut()
+            .find(|installed| installed.id == package.id)
+        {
+            *installed = rolled_back;
+        }
+
+        info!(
+            "{} rolled back from {} to {}",
+            package.full_name('/').color(Color::Blue),
+            (&package.version).color(Color::Yellow),
+            (&target.version).color(Color::Green)
+        );
+        if !package.pinned {
+            info!(
+                "Run `soar pin {}` to keep it from being updated again.",
+                package.full_name('/')
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the portable settings recorded for the package, if any.
+    pub fn portable_options(&self, resolved_package: &ResolvedPackage) -> Result<PortableOptions> {
+        let Some(installed) = self
+            .packages
+            .iter()
+            .find(|installed| installed.full_name('-') == resolved_package.package.full_name('-'))
+        else {
+            return Ok(PortableOptions::default());
+        };
+
+        self.get_portable_options(installed.id)
+    }
+
+    fn get_portable_options(&self, id: i64) -> Result<PortableOptions> {
+        let mut stmt = self.conn.prepare(
+            "SELECT portable_path, portable_home, portable_config
+            FROM portable_package WHERE package_id = ?1",
+        )?;
+        let mut rows = stmt.query_map(params![id], |row| {
+            Ok(PortableOptions {
+                portable: row.get("portable_path")?,
+                portable_home: row.get("portable_home")?,
+                portable_config: row.get("portable_config")?,
+            })
+        })?;
+
+        Ok(rows.next().transpose()?.unwrap_or_default())
+    }
+
+    /// Records the portable settings of the package so updates and reinstalls reapply them.
+    /// Returns the sandbox rules the package runs with, or `None` if it isn't sandboxed.
+    pub fn sandbox_rules(
+        &self,
+        resolved_package: &ResolvedPackage,
+    ) -> Result<Option<SandboxRules>> {
+        let id = self
+            .find_package(resolved_package)
+            .map(|installed| installed.id)
+            .unwrap_or_default();
+        self.get_sandbox_rules(id, &resolved_package.repo_name)
+    }
+
+    fn get_sandbox_rules(&self, id: i64, repo_name: &str) -> Result<Option<SandboxRules>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT net FROM sandbox_rules WHERE package_id = ?1")?;
+        let mut rows = stmt.query_map(params![id], |row| {
+            Ok(SandboxRules {
+                net: row.get::<_, Option<String>>("net")?.as_deref() != Some("deny"),
+                ..Default::default()
+            })
+        })?;
+        let Some(mut rules) = rows.next().transpose()? else {
+            return Ok(CONFIG.is_sandboxed(repo_name).then(SandboxRules::default));
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT path, writable FROM sandbox_paths WHERE package_id = ?1 ORDER BY rowid",
+        )?;
+        let paths = stmt.query_map(params![id], |row| {
+            Ok((
+                row.get::<_, String>("path")?,
+                row.get::<_, bool>("writable")?,
+            ))
+        })?;
+        for path in paths {
+            let (path, writable) = path?;
+            if writable {
+                rules.fs_write.push(path);
+            } else {
+                rules.fs_read.push(path);
+            }
+        }
+
+        Ok(Some(rules))
+    }
+
+    /// Sets or removes sandbox rules of the matching packages and relinks their binaries.
+    pub async fn set_sandbox_rules(
+        &mut self,
+        package_names: &[String],
+        rules: Option<SandboxRules>,
+    ) -> Result<()> {
+        for package_name in package_names {
+            let matching_packages = self.find_matching(package_name, false);
+            if matching_packages.is_empty() {
+                error!("{} is not installed.", package_name);
+                continue;
+            }
+
+            for package in matching_packages {
+                let tx = self.conn.transaction()?;
+                tx.execute(
+                    "DELETE FROM sandbox_rules WHERE package_id = ?1",
+                    params![package.id],
+                )?;
+                tx.execute(
+                    "DELETE FROM sandbox_paths WHERE package_id = ?1",
+                    params![package.id],
+                )?;
+                if let Some(ref rules) = rules {
+                    tx.execute(
+                        "INSERT INTO sandbox_rules (package_id, net) VALUES (?1, ?2)",
+                        params![package.id, if rules.net { "allow" } else { "deny" }],
+                    )?;
+                    let paths = rules
+                        .fs_read
+                        .iter()
+                        .map(|path| (path, false))
+                        .chain(rules.fs_write.iter().map(|path| (path, true)));
+                    for (path, writable) in paths {
+                        tx.execute(
+                            "INSERT INTO sandbox_paths (package_id, path, writable)
+                            VALUES (?1, ?2, ?3)",
+                            params![package.id, path, writable],
+                        )?;
+                    }
+                }
+                tx.commit()
+                    .context("Failed to update sandbox rules in database")?;
+
+                if !package.disabled {
+                    let sandbox = self.get_sandbox_rules(package.id, &package.repo_name)?;
+                    link_bin(
+                        &package.get_install_path(),
+                        &BIN_PATH.join(&package.bin_name),
+                        sandbox.as_ref(),
+                    )
+                    .await?;
+                }
+
+                info!(
+                    "Sandbox rules {} for {}",
+                    if rules.is_some() { "set" } else { "removed" },
+                    package.full_name('/').color(Color::Blue)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn list_sandbox_rules(&self) -> Result<()> {
+        for package in &self.packages {
+            let Some(rules) = self.get_sandbox_rules(package.id, &package.repo_name)? else {
+                continue;
+            };
+
+            info!(
+                "- {} read: [{}] write: [{}] net: {}",
+                package.full_name('/').color(Color::Blue),
+                rules.fs_read.join(", ").color(Color::Yellow),
+                rules.fs_write.join(", ").color(Color::Yellow),
+                if rules.net {
+                    "allow".color(Color::Green)
+                } else {
+                    "deny".color(Color::Red)
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Disables or enables the matching packages. Disabling removes the binary symlink and
+    /// desktop integration but keeps the package files, enabling links them again.
+    pub async fn disable_packages(
+        &mut self,
+        package_names: &[String],
+        disabled: bool,
+    ) -> Result<()> {
+        for package_name in package_names {
+            let matching_packages = self.find_matching(package_name, false);
+            if matching_packages.is_empty() {
+                error!("{} is not installed.", package_name);
+                continue;
+            }
+
+            for package in matching_packages {
+                if package.disabled == disabled {
+                    warn!(
+                        "{} is already {}",
+                        package.full_name('/').color(Color::Blue),
+                        if disabled { "disabled" } else { "enabled" }
+                    );
+                    continue;
+                }
+
+                let install_path = package.get_install_path();
+                if disabled {
+                    let symlink_path = BIN_PATH.join(&package.bin_name);
+                    if bin_target(&symlink_path).as_ref() == Some(&install_path) {
+                        fs::remove_file(&symlink_path).await?;
+                    }
+                    remove_applinks(&package.name, &package.bin_name, &install_path).await?;
+                } else {
+                    let sandbox = self.get_sandbox_rules(package.id, &package.repo_name)?;
+                    link_bin(
+                        &install_path,
+                        &BIN_PATH.join(&package.bin_name),
+                        sandbox.as_ref(),
+                    )
+                    .await?;
+                    restore_applinks(&package.name, &package.bin_name, &install_path).await?;
+                }
+
+                self.conn
+                    .execute(
+                        "UPDATE packages SET disabled = ?1 WHERE id = ?2",
+                        params![disabled, package.id],
+                    )
+                    .context("Failed to update package in database")?;
+                if let Some(installed) = self
+                    .packages
+                    .iter_mut()
+                    .find(|installed| installed.id == package.id)
+                {
+                    installed.disabled = disabled;
+                }
+
+                info!(
+                    "{} is {}",
+                    package.full_name('/').color(Color::Blue),
+                    if disabled { "disabled" } else { "enabled" }
+                );
+            }
+        }
 
         Ok(())
     }
@@ -127,15 +803,20 @@ impl InstalledPackages {
         Ok(())
     }
 
-    pub async fn save(&self) -> Result<()> {
-        let path = INSTALL_TRACK_PATH.join("latest");
-
-        let content = rmp_serde::to_vec(&self)
-            .context("Failed to serialize installed packages to MessagePack")?;
-
-        fs::write(&path, content).await.context(format!(
+    /// Writes the in-memory state of all tracked packages to the database. Packages that
+    /// aren't in the database yet are inserted.
+    pub async fn save(&mut self) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for package in self.packages.iter_mut() {
+            if package.id == 0 {
+                package.id = insert_package(&tx, package, None)?;
+            } else {
+                update_package(&tx, package.id, package, None)?;
+            }
+        }
+        tx.commit().context(format!(
             "Failed to write to {}",
-            path.to_string_lossy().color(Color::Red)
+            CORE_DB_PATH.to_string_lossy().color(Color::Red)
         ))?;
 
         Ok(())
@@ -169,7 +850,7 @@ impl InstalledPackages {
 
         resolved_packages.iter().for_each(|package| {
             info!(
-                "- [{}] {1}:{1}-{2} ({3}) ({4})",
+                "- [{}] {1}:{1}-{2} ({3}) ({4}){5}",
                 package.collection.clone().color(Color::BrightGreen),
                 package.name.clone().color(Color::Blue),
                 package.version.clone().color(Color::Green),
@@ -177,7 +858,15 @@ impl InstalledPackages {
                     .timestamp
                     .format("%Y-%m-%d %H:%M:%S")
                     .color(Color::Yellow),
-                format_bytes(package.size).color(Color::Magenta)
+                format_bytes(package.size).color(Color::Magenta),
+                [
+                    (package.pinned, " [pinned]"),
+                    (package.disabled, " [disabled]")
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, label)| label.color(Color::BrightCyan))
+                .collect::<String>()
             );
 
             let (count, size) = total.get(&package.collection).unwrap_or(&(0, 0));
@@ -216,28 +905,28 @@ impl InstalledPackages {
 
     pub async fn use_package(&self, resolved_package: &ResolvedPackage) -> Result<()> {
         if let Some(installed) = self.find_package(resolved_package) {
+            if installed.disabled {
+                return Err(anyhow::anyhow!(
+                    "{} is disabled. Enable it first.",
+                    installed.full_name('/').color(Color::Blue)
+                ));
+            }
+
             let install_path = resolved_package
                 .package
                 .get_install_path(&installed.checksum);
-            let symlink_path = &BIN_PATH.join(&installed.bin_name);
-
-            if symlink_path.exists() {
-                if xattr::get_deref(symlink_path, "user.managed_by")?.as_deref() != Some(b"soar") {
-                    return Err(anyhow::anyhow!(
-                        "{} is not managed by soar",
-                        symlink_path.to_string_lossy().color(Color::Blue)
-                    ));
-                }
-                fs::remove_file(symlink_path).await?;
-            }
+            let sandbox = self.get_sandbox_rules(installed.id, &installed.repo_name)?;
+            link_bin(
+                &install_path,
+                &BIN_PATH.join(&installed.bin_name),
+                sandbox.as_ref(),
+            )
+            .await?;
 
-            fs::symlink(&install_path, symlink_path)
-                .await
-                .context(format!(
-                    "Failed to link {} to {}",
-                    install_path.to_string_lossy().color(Color::Blue),
-                    symlink_path.to_string_lossy().color(Color::Blue)
-                ))?;
+            let portable = self.get_portable_options(installed.id)?;
+            if !portable.is_empty() {
+                reapply_portable_dir(&installed.bin_name, &install_path, portable).await?;
+            }
         } else {
             return Err(anyhow::anyhow!("NOT_INSTALLED"));
         }
@@ -246,6 +935,60 @@ impl InstalledPackages {
     }
 }
 
+/// Points the binary, desktop integration and portable directories of the package at the version
+/// installed at `to` instead of the one at `from`.
+async fn switch_version(
+    package: &InstalledPackage,
+    sandbox: Option<&SandboxRules>,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    if !package.disabled {
+        link_bin(to, &BIN_PATH.join(&package.bin_name), sandbox).await?;
+        remove_applinks(&package.name, &package.bin_name, from).await?;
+        restore_applinks(&package.name, &package.bin_name, to).await?;
+    }
+    move_portable_dirs(&package.bin_name, from, to).await
+}
+
+/// Regenerates stale sandbox wrappers in the bin directory, so they keep working once soar is
+/// moved or updated. The database is only opened if there is any.
+pub async fn refresh_sandbox_wrappers() -> Result<()> {
+    let mut entries = match fs::read_dir(&*BIN_PATH).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if is_stale_wrapper(&entry.path()) {
+            return InstalledPackages::new()
+                .await?
+                .refresh_sandbox_wrappers()
+                .await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn link_bin(
+    install_path: &Path,
+    symlink_path: &Path,
+    sandbox: Option<&SandboxRules>,
+) -> Result<()> {
+    if symlink_path.exists() {
+        if xattr::get_deref(symlink_path, "user.managed_by")?.as_deref() != Some(b"soar") {
+            return Err(anyhow::anyhow!(
+                "{} is not managed by soar",
+                symlink_path.to_string_lossy().color(Color::Blue)
+            ));
+        }
+        fs::remove_file(symlink_path).await?;
+    }
+
+    create_bin_link(install_path, symlink_path, sandbox).await
+}
+
 impl InstalledPackage {
     pub fn full_name(&self, join_char: char) -> String {
         let family_prefix = self
@@ -264,3 +1007,197 @@ impl InstalledPackage {
         self.get_install_dir().join(&self.bin_name)
     }
 }
+
+fn map_installed_package(row: &Row) -> rusqlite::Result<InstalledPackage> {
+    let family: String = row.get("family")?;
+    let size: i64 = row.get("size")?;
+    let installed_date: String = row.get("installed_date")?;
+
+    Ok(InstalledPackage {
+        id: row.get("id")?,
+        repo_name: row.get("repo_name")?,
+        collection: row.get("collection")?,
+        name: row.get("pkg")?,
+        family: (!family.is_empty()).then_some(family),
+        bin_name: row.get("pkg_name")?,
+        version: row.get("version")?,
+        checksum: row.get("checksum")?,
+        size: size as u64,
+        timestamp: DateTime::parse_from_rfc3339(&installed_date)
+            .map(|date| date.with_timezone(&Utc))
+            .unwrap_or_default(),
+        pinned: row.get("pinned")?,
+        disabled: row.get("disabled")?,
+    })
+}
+
+fn insert_version(tx: &Transaction, installed: &InstalledPackage) -> Result<()> {
+    tx.execute(
+        "INSERT INTO package_versions (
+            package_id, version, checksum, size, installed_path, installed_date
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            installed.id,
+            installed.version,
+            installed.checksum,
+            installed.size as i64,
+            installed.get_install_path().to_string_lossy(),
+            installed.timestamp.to_rfc3339(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Drops all but the `keep` most recent previous versions of the package and returns the
+/// install paths of the dropped ones.
+fn prune_versions(tx: &Transaction, package_id: i64, keep: usize) -> Result<Vec<PathBuf>> {
+    let pruned = {
+        let mut stmt = tx.prepare(
+            "SELECT id, installed_path FROM package_versions
+            WHERE package_id = ?1 ORDER BY id DESC LIMIT -1 OFFSET ?2",
+        )?;
+        let rows = stmt
+            .query_map(params![package_id, keep as i64], |row| {
+                Ok((
+                    row.get::<_, i64>("id")?,
+                    row.get::<_, String>("installed_path")?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (id, _) in &pruned {
+        tx.execute("DELETE FROM package_versions WHERE id = ?1", params![id])?;
+    }
+
+    Ok(pruned
+        .into_iter()
+        .map(|(_, path)| PathBuf::from(path))
+        .collect())
+}
+
+fn save_portable_options(
+    tx: &Transaction,
+    package_id: i64,
+    options: &PortableOptions,
+) -> Result<()> {
+    tx.execute(
+        "DELETE FROM portable_package WHERE package_id = ?1",
+        params![package_id],
+    )?;
+    tx.execute(
+        "INSERT INTO portable_package (package_id, portable_path, portable_home, portable_config)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            package_id,
+            options.portable,
+            options.portable_home,
+            options.portable_config
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Removes the install directories of versions dropped from the history.
+pub async fn remove_versions(install_paths: &[PathBuf]) {
+    for install_dir in install_paths.iter().filter_map(|path| path.parent()) {
+        if !install_dir.starts_with(&*PACKAGES_PATH) || !install_dir.exists() {
+            continue;
+        }
+        if let Err(e) = fs::remove_dir_all(install_dir).await {
+            warn!(
+                "Failed to remove previous version at {}: {}",
+                install_dir.display().color(Color::Blue),
+                e
+            );
+        }
+    }
+}
+
+fn insert_package(
+    tx: &Transaction,
+    installed: &InstalledPackage,
+    package: Option<&Package>,
+) -> Result<i64> {
+    let package = package.cloned().unwrap_or_default();
+    tx.execute(
+        "INSERT INTO packages (
+            repo_name, collection, family, pkg_name, pkg, pkg_id, description, version,
+            size, checksum, build_date, build_script, build_log, category,
+            installed_path, installed_date, pinned, disabled
+        ) VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18
+        )",
+        params![
+            installed.repo_name,
+            installed.collection,
+            installed.family.as_deref().unwrap_or_default(),
+            installed.bin_name,
+            installed.name,
+            package.pkg_id.unwrap_or_default(),
+            package.description,
+            installed.version,
+            installed.size as i64,
+            installed.checksum,
+            package.build_date,
+            package.build_script,
+            package.build_log,
+            package.category,
+            installed.get_install_path().to_string_lossy(),
+            installed.timestamp.to_rfc3339(),
+            installed.pinned,
+            installed.disabled,
+        ],
+    )?;
+
+    Ok(tx.last_insert_rowid())
+}
+
+fn update_package(
+    tx: &Transaction,
+    id: i64,
+    installed: &InstalledPackage,
+    package: Option<&Package>,
+) -> Result<()> {
+    tx.execute(
+        "UPDATE packages SET
+            repo_name = ?1, collection = ?2, version = ?3, size = ?4, checksum = ?5,
+            installed_path = ?6, installed_date = ?7, pinned = ?8, disabled = ?9
+        WHERE id = ?10",
+        params![
+            installed.repo_name,
+            installed.collection,
+            installed.version,
+            installed.size as i64,
+            installed.checksum,
+            installed.get_install_path().to_string_lossy(),
+            installed.timestamp.to_rfc3339(),
+            installed.pinned,
+            installed.disabled,
+            id,
+        ],
+    )?;
+
+    if let Some(package) = package {
+        tx.execute(
+            "UPDATE packages SET
+                pkg_id = ?1, description = ?2, build_date = ?3, build_script = ?4,
+                build_log = ?5, category = ?6
+            WHERE id = ?7",
+            params![
+                package.pkg_id.as_deref().unwrap_or_default(),
+                package.description,
+                package.build_date,
+                package.build_script,
+                package.build_log,
+                package.category,
+                id,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
@@ -1,38 +1,144 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OpenFlags};
 use tokio::fs;
-use tracing::warn;
+use tracing::{debug, warn};
 
-use crate::core::config::Repository;
+use crate::core::{
+    color::{Color, ColorExt},
+    config::{Repository, CONFIG},
+};
 
 use super::fetcher::MetadataFetcher;
 
 pub struct MetadataLoader;
 
+/// Sync state of a repository.
+pub struct RepositoryStatus {
+    /// Whether metadata of the repository is cached locally.
+    pub cached: bool,
+
+    /// When the repository was last synced successfully.
+    pub last_sync: Option<DateTime<Utc>>,
+
+    /// Error of the last sync, if it failed and cached metadata is used instead.
+    pub stale: Option<String>,
+}
+
 impl MetadataLoader {
     pub fn new() -> Self {
         Self
     }
 
-    pub async fn execute(&self, repo: &Repository, fetcher: &MetadataFetcher) -> Result<Vec<u8>> {
-        let checksum = fetcher.checksum(repo).await;
-
-        if let Ok(checksum) = checksum {
-            let checksum_path = repo
-                .get_path()
-                .with_file_name(format!("{}.remote.bsum", repo.name));
-            let local_checksum = fs::read(&checksum_path).await.unwrap_or_default();
-            if checksum != local_checksum {
-                warn!("Local registry is outdated. Refetching...");
-                let content = fetcher.execute(repo).await;
-                fs::write(checksum_path, &checksum).await?;
-                return content;
+    /// Refetches the metadata of the repository if it changed on the remote. The remote is
+    /// only checked once `sync_interval` has elapsed since the last sync, unless `force` is set.
+    /// Deltas published by the repository are applied when available, to avoid downloading
+    /// the full metadata.
+    pub async fn execute(
+        &self,
+        repo: &Repository,
+        fetcher: &MetadataFetcher,
+        force: bool,
+    ) -> Result<()> {
+        if CONFIG.is_offline() || (!force && !self.is_sync_due(repo).await) {
+            return Ok(());
+        }
+
+        let checksum = fetcher.checksum(repo).await?;
+        let checksum_path = repo
+            .get_path()
+            .with_file_name(format!("{}.remote.bsum", repo.name));
+        let local_checksum = fs::read(&checksum_path).await.unwrap_or_default();
+        if checksum != local_checksum {
+            warn!(
+                "Local registry of {} is outdated. Refetching...",
+                repo.name.clone().color(Color::Yellow)
+            );
+            let updated = !local_checksum.is_empty()
+                && fetcher
+                    .apply_deltas(repo, &local_checksum, &checksum)
+                    .await
+                    .inspect_err(|e| {
+                        debug!(
+                            "No metadata delta for {}: {}",
+                            repo.name.clone().color(Color::Yellow),
+                            e
+                        )
+                    })
+                    .is_ok();
+            if !updated {
+                fetcher.execute(repo, Some(&checksum)).await?;
             }
+            fs::write(checksum_path, &checksum).await?;
         }
+        self.mark_synced(repo).await?;
+
+        Ok(())
+    }
+
+    /// Whether `sync_interval` has elapsed since the repository was last synced.
+    async fn is_sync_due(&self, repo: &Repository) -> bool {
+        let last_sync = fs::read_to_string(repo.last_sync_path())
+            .await
+            .ok()
+            .and_then(|content| content.trim().parse::<u64>().ok());
+        let Some(last_sync) = last_sync else {
+            return true;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        now.saturating_sub(last_sync) >= repo.sync_interval().as_secs()
+    }
+
+    /// Records the current time as the last sync of the repository.
+    pub async fn mark_synced(&self, repo: &Repository) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let _ = fs::remove_file(repo.stale_path()).await;
+        fs::write(repo.last_sync_path(), now.to_string())
+            .await
+            .context("Failed to record last sync time")
+    }
+
+    /// Records that the repository failed to sync and its cached metadata is in use.
+    pub async fn mark_stale(&self, repo: &Repository, error: &str) -> Result<()> {
+        fs::write(repo.stale_path(), error)
+            .await
+            .context("Failed to record sync failure")
+    }
 
-        let path = repo.get_path();
-        let content = fs::read(path)
+    /// Returns the sync state of the repository as recorded on disk.
+    pub async fn status(&self, repo: &Repository) -> RepositoryStatus {
+        let last_sync = fs::read_to_string(repo.last_sync_path())
             .await
+            .ok()
+            .and_then(|content| content.trim().parse::<i64>().ok())
+            .and_then(|secs| DateTime::from_timestamp(secs, 0));
+        let stale = fs::read_to_string(repo.stale_path()).await.ok();
+
+        RepositoryStatus {
+            cached: repo.get_path().exists(),
+            last_sync,
+            stale,
+        }
+    }
+
+    /// Opens the metadata database of the repository and checks that it is readable.
+    pub fn open(&self, repo: &Repository) -> Result<Connection> {
+        let conn = Connection::open_with_flags(repo.get_path(), OpenFlags::SQLITE_OPEN_READ_WRITE)
             .context("Failed to load registry path.")?;
-        Ok(content)
+        conn.query_row("SELECT COUNT(*) FROM packages", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .context("Failed to read registry.")?;
+
+        Ok(conn)
     }
 }
@@ -1,21 +1,23 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use serde::Deserialize;
+use futures::{stream, StreamExt};
+use reqwest::Url;
+use rusqlite::Connection;
 use termion::cursor;
 use tokio::{fs, sync::Mutex};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use fetcher::MetadataFetcher;
 use installed::InstalledPackages;
 use loader::MetadataLoader;
-use storage::{PackageStorage, RepositoryPackages};
+use storage::PackageStorage;
 
 use crate::{
     core::{
         color::{Color, ColorExt},
-        config::CONFIG,
-        util::{get_terminal_width, interactive_ask, wrap_text, AskType},
+        config::{Repository, CONFIG},
+        util::{file_url_path, get_terminal_width, interactive_ask, wrap_text, AskType},
     },
     package::{
         image::get_package_image_string, parse_package_query, update::Updater, ResolvedPackage,
@@ -23,6 +25,7 @@ use crate::{
 };
 
 mod fetcher;
+pub mod index;
 pub mod installed;
 mod loader;
 mod storage;
@@ -34,12 +37,22 @@ pub struct PackageRegistry {
 
 impl PackageRegistry {
     pub async fn new() -> Result<Self> {
+        Self::init(false).await
+    }
+
+    /// Creates the registry after checking the metadata of all repositories against their
+    /// remotes, regardless of `sync_interval`.
+    pub async fn sync() -> Result<Self> {
+        Self::init(true).await
+    }
+
+    async fn init(force_sync: bool) -> Result<Self> {
         let loader = MetadataLoader::new();
         let fetcher = MetadataFetcher::new();
         let mut storage = PackageStorage::new();
         let installed_packages = Arc::new(Mutex::new(InstalledPackages::new().await?));
 
-        Self::load_or_fetch_packages(&loader, &fetcher, &mut storage).await?;
+        Self::load_or_fetch_packages(&loader, &fetcher, &mut storage, force_sync).await?;
 
         Ok(Self {
             storage,
@@ -47,41 +60,102 @@ impl PackageRegistry {
         })
     }
 
+    /// Loads the metadata of all repositories, fetching up to `parallel_limit` of them at
+    /// once. Repositories that fail to load are reported and skipped.
     pub async fn load_or_fetch_packages(
         loader: &MetadataLoader,
         fetcher: &MetadataFetcher,
         storage: &mut PackageStorage,
+        force_sync: bool,
     ) -> Result<()> {
-        for repo in &CONFIG.repositories {
-            let path = repo.get_path();
-            let content = if path.exists() {
-                loader.execute(repo, fetcher).await?
-            } else {
-                let checksum = fetcher.checksum(repo).await?;
-                let checksum_path = repo
-                    .get_path()
-                    .with_file_name(format!("{}.remote.bsum", repo.name));
-                fs::write(checksum_path, &checksum).await?;
-
-                fetcher.execute(repo).await?
-            };
+        let limit = if CONFIG.parallel.unwrap_or_default() {
+            CONFIG.parallel_limit.unwrap_or(2).max(1) as usize
+        } else {
+            1
+        };
 
-            let mut de = rmp_serde::Deserializer::new(&content[..]);
-            let packages = match RepositoryPackages::deserialize(&mut de) {
-                Ok(packages) => packages,
-                Err(_) => {
-                    error!("Metadata is invalid. Refetching...");
-                    let content = fetcher.execute(repo).await?;
-                    let mut de = rmp_serde::Deserializer::new(&content[..]);
-                    RepositoryPackages::deserialize(&mut de)?
+        let mut repositories = CONFIG
+            .repositories
+            .iter()
+            .filter(|repo| !repo.is_disabled())
+            .collect::<Vec<_>>();
+        repositories.sort_by_key(|repo| std::cmp::Reverse(repo.priority()));
+
+        let results = stream::iter(&repositories)
+            .map(|repo| async move {
+                let result = Self::load_repository(loader, fetcher, repo, force_sync).await;
+                (repo, result)
+            })
+            .buffered(limit)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut failed = 0;
+        for (repo, result) in results {
+            match result {
+                Ok(conn) => storage.add_repository(&repo.name, conn),
+                Err(e) => {
+                    error!(
+                        "Failed to load repository {}: {}",
+                        repo.name.clone().color(Color::Yellow),
+                        e
+                    );
+                    failed += 1;
                 }
-            };
-            storage.add_repository(&repo.name, packages);
+            }
+        }
+
+        if failed > 0 && failed == repositories.len() {
+            anyhow::bail!("Failed to load any repository");
         }
 
         Ok(())
     }
 
+    async fn load_repository(
+        loader: &MetadataLoader,
+        fetcher: &MetadataFetcher,
+        repo: &Repository,
+        force_sync: bool,
+    ) -> Result<Connection> {
+        let path = repo.get_path();
+        if CONFIG.is_offline() && !path.exists() {
+            anyhow::bail!("No cached metadata. Sync it once without --offline.");
+        }
+        if path.exists() {
+            if let Err(e) = loader.execute(repo, fetcher, force_sync).await {
+                warn!(
+                    "Failed to sync {}: {}. Using cached metadata.",
+                    repo.name.clone().color(Color::Yellow),
+                    e
+                );
+                loader.mark_stale(repo, &e.to_string()).await?;
+            }
+        } else {
+            let checksum = fetcher.checksum(repo).await?;
+            fetcher.execute(repo, Some(&checksum)).await?;
+            let checksum_path = repo
+                .get_path()
+                .with_file_name(format!("{}.remote.bsum", repo.name));
+            fs::write(checksum_path, &checksum).await?;
+            loader.mark_synced(repo).await?;
+        }
+
+        match loader.open(repo) {
+            Ok(conn) => Ok(conn),
+            Err(e) if CONFIG.is_offline() => Err(e),
+            Err(_) => {
+                error!(
+                    "Metadata of {} is invalid. Refetching...",
+                    repo.name.clone().color(Color::Yellow)
+                );
+                fetcher.execute(repo, None).await?;
+                loader.open(repo)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn install_packages(
         &self,
         package_names: &[String],
@@ -91,6 +165,7 @@ impl PackageRegistry {
         portable_config: Option<String>,
         yes: bool,
         quiet: bool,
+        allow_checksum_mismatch: bool,
     ) -> Result<()> {
         self.storage
             .install_packages(
@@ -102,16 +177,11 @@ impl PackageRegistry {
                 portable_config,
                 yes,
                 quiet,
+                allow_checksum_mismatch,
             )
             .await
     }
 
-    pub async fn remove_packages(&self, package_names: &[String], exact: bool) -> Result<()> {
-        self.storage
-            .remove_packages(package_names, self.installed_packages.clone(), exact)
-            .await
-    }
-
     pub async fn search(
         &self,
         package_name: &str,
@@ -234,18 +304,31 @@ impl PackageRegistry {
                         .to_string()
                         .color(Color::BrightMagenta),
                 ));
+                if installed.pinned {
+                    data.push(("Pinned", "Yes".color(Color::BrightCyan)));
+                }
+                if installed.disabled {
+                    data.push(("Disabled", "Yes".color(Color::BrightCyan)));
+                }
             }
 
-            let pkg_image = get_package_image_string(&pkg).await;
-
-            let indent = 32;
-
-            info!(
-                "{}{}{}",
-                pkg_image,
-                cursor::Up(15),
+            let indent = match get_package_image_string(&pkg).await {
+                Some(pkg_image) => {
+                    info!(
+                        "{}{}{}",
+                        pkg_image,
+                        cursor::Up(15),
+                        cursor::Right(32).to_string()
+                    );
+                    32
+                }
+                None => 0,
+            };
+            let margin = if indent > 0 {
                 cursor::Right(indent).to_string()
-            );
+            } else {
+                String::new()
+            };
 
             data.iter().for_each(|(k, v)| {
                 let value = strip_ansi_escapes::strip_str(v);
@@ -258,7 +341,7 @@ impl PackageRegistry {
                         indent,
                     );
 
-                    info!("{}{}", cursor::Right(indent).to_string(), line);
+                    info!("{}{}", margin, line);
                 }
             });
 
@@ -267,9 +350,14 @@ impl PackageRegistry {
         Ok(())
     }
 
-    pub async fn update(&self, package_names: Option<&[String]>, quiet: bool) -> Result<()> {
+    pub async fn update(
+        &self,
+        package_names: Option<&[String]>,
+        quiet: bool,
+        allow_checksum_mismatch: bool,
+    ) -> Result<()> {
         let updater = Updater::new(package_names);
-        updater.execute(self, quiet).await
+        updater.execute(self, quiet, allow_checksum_mismatch).await
     }
 
     pub async fn info(&self, package_names: Option<&[String]>) -> Result<()> {
@@ -310,8 +398,20 @@ impl PackageRegistry {
         self.storage.inspect(package_name, inspect_type).await
     }
 
-    pub async fn run(&self, command: &[String], yes: bool) -> Result<()> {
-        self.storage.run(command, yes).await
+    pub async fn run(
+        &self,
+        command: &[String],
+        yes: bool,
+        allow_checksum_mismatch: bool,
+    ) -> Result<()> {
+        self.storage
+            .run(
+                command,
+                yes,
+                self.installed_packages.clone(),
+                allow_checksum_mismatch,
+            )
+            .await
     }
 
     pub async fn use_package(&self, package_name: &str, quiet: bool) -> Result<()> {
@@ -341,6 +441,7 @@ impl PackageRegistry {
                             None,
                             false,
                             quiet,
+                            false,
                         )
                         .await?;
 
@@ -353,6 +454,79 @@ impl PackageRegistry {
     }
 }
 
+/// Checks that a repository about to be added to config is usable.
+pub async fn validate_repository(repo: &Repository) -> Result<()> {
+    if repo.name.is_empty()
+        || repo.name.starts_with('.')
+        || repo.name.contains(|c: char| c == '/' || c.is_whitespace())
+    {
+        anyhow::bail!(
+            "Invalid repository name {}. Use a name without slashes or whitespace.",
+            repo.name.clone().color(Color::Blue)
+        );
+    }
+    for url in repo.base_urls() {
+        if let Some(path) = file_url_path(&url) {
+            if !path.is_dir() {
+                anyhow::bail!(
+                    "Repository directory {} does not exist",
+                    path.to_string_lossy().color(Color::Blue)
+                );
+            }
+        } else if Url::parse(&url).is_err() {
+            anyhow::bail!("Invalid repository URL {}", url.color(Color::Blue));
+        } else {
+            CONFIG.ensure_online("add remote repositories")?;
+        }
+    }
+
+    MetadataFetcher::new().validate(repo).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to fetch metadata from {}: {}",
+            (&repo.url).color(Color::Blue),
+            e
+        )
+    })
+}
+
+/// Prints the sync state of every configured repository.
+pub async fn print_repository_status() {
+    let loader = MetadataLoader::new();
+    for repo in &CONFIG.repositories {
+        let name = repo.name.clone().color(Color::Blue);
+        if repo.is_disabled() {
+            info!("{}: {}", name, "disabled".color(Color::BrightBlack));
+            continue;
+        }
+
+        let status = loader.status(repo).await;
+        if !status.cached {
+            warn!("{}: {}", name, "not synced".color(Color::Red));
+            continue;
+        }
+
+        let last_sync = status
+            .last_sync
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+        match status.stale {
+            Some(error) => warn!(
+                "{}: {}, last synced {} ({})",
+                name,
+                "stale".color(Color::Yellow),
+                last_sync,
+                error.trim()
+            ),
+            None => info!(
+                "{}: {}, last synced {}",
+                name,
+                "ok".color(Color::Green),
+                last_sync
+            ),
+        }
+    }
+}
+
 pub fn select_single_package(packages: &[ResolvedPackage]) -> Result<&ResolvedPackage> {
     info!(
         "Multiple packages available for {}",
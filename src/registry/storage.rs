@@ -1,5 +1,4 @@
 use std::{
-    collections::HashMap,
     fs::File,
     io::BufReader,
     sync::{
@@ -11,7 +10,7 @@ use std::{
 use anyhow::{Context, Result};
 use futures::{future::join_all, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use serde::{Deserialize, Serialize};
+use rusqlite::{params, Connection, Row};
 use tokio::{
     fs,
     sync::{Mutex, Semaphore},
@@ -24,6 +23,7 @@ use crate::{
         config::CONFIG,
         constant::CACHE_PATH,
         file::{get_file_type, FileType},
+        http::CLIENT,
         util::{build_path, format_bytes, interactive_ask, AskType},
     },
     error,
@@ -35,26 +35,110 @@ use crate::{
 
 use super::select_single_package;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PackageStorage {
-    repository: HashMap<String, RepositoryPackages>,
+    repositories: Vec<(String, Connection)>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct RepositoryPackages {
-    #[serde(flatten)]
-    pub collection: HashMap<String, HashMap<String, Vec<Package>>>,
+/// Selects a package together with the data spread across the metadata tables. The
+/// columns are mapped back to a [`Package`] by `map_package`.
+const PACKAGE_QUERY: &str = "
+    SELECT
+        p.pkg, p.pkg_id, p.pkg_name, p.description, p.version, p.download_url, p.size,
+        p.checksum, p.build_date, p.build_script, p.build_log, p.category, p.desktop,
+        c.name AS collection, f.name AS family, i.url AS icon,
+        (SELECT GROUP_CONCAT(note, '<br>') FROM notes WHERE package_id = p.id) AS note,
+        (SELECT GROUP_CONCAT(url, ' ') FROM homepages WHERE package_id = p.id) AS homepage,
+        (SELECT GROUP_CONCAT(url, ' ') FROM source_urls WHERE package_id = p.id) AS src_url,
+        (
+            SELECT GROUP_CONCAT(pf.name, ',')
+            FROM provides pr
+            JOIN families pf ON pf.id = pr.family_id
+            WHERE pr.package_id = p.id
+        ) AS provides
+    FROM packages p
+    JOIN collections c ON c.id = p.collection_id
+    JOIN families f ON f.id = p.family_id
+    JOIN icons i ON i.id = p.icon_id";
+
+fn map_package(row: &Row) -> rusqlite::Result<Package> {
+    let family: String = row.get("family")?;
+    let size: i64 = row.get("size")?;
+
+    Ok(Package {
+        pkg: row.get("pkg")?,
+        pkg_name: row.get("pkg_name")?,
+        description: row
+            .get::<_, Option<String>>("description")?
+            .unwrap_or_default(),
+        note: row.get::<_, Option<String>>("note")?.unwrap_or_default(),
+        version: row.get("version")?,
+        download_url: row.get("download_url")?,
+        size: format_bytes(size as u64),
+        bsum: row.get("checksum")?,
+        build_date: row.get("build_date")?,
+        src_url: row.get::<_, Option<String>>("src_url")?.unwrap_or_default(),
+        homepage: row
+            .get::<_, Option<String>>("homepage")?
+            .unwrap_or_default(),
+        build_script: row.get("build_script")?,
+        build_log: row.get("build_log")?,
+        category: row
+            .get::<_, Option<String>>("category")?
+            .unwrap_or_default(),
+        provides: row
+            .get::<_, Option<String>>("provides")?
+            .unwrap_or_default(),
+        icon: row.get("icon")?,
+        desktop: row.get("desktop")?,
+        pkg_id: row.get("pkg_id")?,
+        family: (!family.is_empty()).then_some(family),
+    })
 }
 
 impl PackageStorage {
     pub fn new() -> Self {
         Self {
-            repository: HashMap::new(),
+            repositories: Vec::new(),
         }
     }
 
-    pub fn add_repository(&mut self, repo_name: &str, packages: RepositoryPackages) {
-        self.repository.insert(repo_name.to_owned(), packages);
+    pub fn add_repository(&mut self, repo_name: &str, conn: Connection) {
+        self.repositories.push((repo_name.to_owned(), conn));
+    }
+
+    /// Runs `sql` against the metadata database of every repository and collects the
+    /// resulting packages along with the value produced by `extra` for each row.
+    fn query_packages<T, P, F>(&self, sql: &str, params: P, extra: F) -> Vec<(ResolvedPackage, T)>
+    where
+        P: rusqlite::Params + Clone,
+        F: Fn(&Row) -> rusqlite::Result<T>,
+    {
+        let mut resolved_packages = Vec::new();
+        for (repo_name, conn) in &self.repositories {
+            let result = conn.prepare_cached(sql).and_then(|mut stmt| {
+                stmt.query_map(params.clone(), |row| {
+                    Ok((
+                        ResolvedPackage {
+                            repo_name: repo_name.clone(),
+                            collection: row.get("collection")?,
+                            package: map_package(row)?,
+                        },
+                        extra(row)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+            });
+
+            match result {
+                Ok(packages) => resolved_packages.extend(packages),
+                Err(err) => warn!(
+                    "Failed to query packages from {}: {}",
+                    repo_name.color(Color::Yellow),
+                    err
+                ),
+            }
+        }
+        resolved_packages
     }
 
     pub fn resolve_package(&self, package_name: &str, yes: bool) -> Result<ResolvedPackage> {
@@ -63,7 +147,23 @@ impl PackageStorage {
             .get_packages(&pkg_query)
             .ok_or_else(|| anyhow::anyhow!("Package {} not found", package_name))?;
 
-        packages.sort_by(|a, b| a.package.family.cmp(&b.package.family));
+        // Only candidates from the highest priority repositories are considered, so a package
+        // from a preferred repository is picked without asking.
+        let priority = |package: &ResolvedPackage| {
+            CONFIG
+                .repositories
+                .iter()
+                .find(|repo| repo.name == package.repo_name)
+                .map(|repo| repo.priority())
+                .unwrap_or_default()
+        };
+        packages.sort_by(|a, b| {
+            priority(b)
+                .cmp(&priority(a))
+                .then_with(|| a.package.family.cmp(&b.package.family))
+        });
+        let top_priority = priority(&packages[0]);
+        packages.retain(|package| priority(package) == top_priority);
 
         let package = if yes || packages.len() == 1 {
             &packages[0]
@@ -74,6 +174,7 @@ impl PackageStorage {
         Ok(package.to_owned())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn install_packages(
         &self,
         package_names: &[String],
@@ -84,6 +185,7 @@ impl PackageStorage {
         portable_config: Option<String>,
         yes: bool,
         quiet: bool,
+        allow_checksum_mismatch: bool,
     ) -> Result<()> {
         let resolved_packages: Vec<ResolvedPackage> = package_names
             .iter()
@@ -194,6 +296,7 @@ impl PackageStorage {
                             portable_home,
                             portable_config,
                             multi_progress,
+                            allow_checksum_mismatch,
                         )
                         .await
                     {
@@ -228,6 +331,7 @@ impl PackageStorage {
                         } else {
                             Some(multi_progress.clone())
                         },
+                        allow_checksum_mismatch,
                     )
                     .await
                 {
@@ -252,122 +356,35 @@ impl PackageStorage {
         Ok(())
     }
 
-    pub async fn remove_packages(
-        &self,
-        package_names: &[String],
-        installed_packages: Arc<Mutex<InstalledPackages>>,
-        exact: bool,
-    ) -> Result<()> {
-        let mut mut_guard = installed_packages.lock().await;
-        let installed_packages = &mut_guard.packages;
-
-        let mut packages_to_remove = Vec::new();
-        for package_name in package_names.iter() {
-            let query = parse_package_query(package_name);
-            let mut matching_packages = Vec::new();
-
-            for package in installed_packages {
-                if package.name != query.name {
-                    continue;
-                }
-                if let Some(ref ckey) = query.collection {
-                    if package.collection != *ckey {
-                        continue;
-                    }
-                }
-
-                let family_matches = match (&query.family, &package.family) {
-                    (None, None) => true,
-                    (None, Some(_)) => !exact,
-                    (Some(ref query_family), Some(ref package_family)) => {
-                        query_family == package_family
-                    }
-                    _ => false,
-                };
-
-                if family_matches {
-                    matching_packages.push(package.clone());
-                }
-            }
-
-            if matching_packages.is_empty() {
-                error!("{} is not installed.", package_name);
-            } else {
-                packages_to_remove.extend(matching_packages);
-            }
-        }
-
-        for package in packages_to_remove {
-            mut_guard.remove(&package).await?;
-        }
-
-        Ok(())
-    }
-
     pub fn list_packages(&self, collection: Option<&str>) -> Vec<ResolvedPackage> {
-        let mut packages: Vec<ResolvedPackage> = self
-            .repository
-            .iter()
-            .flat_map(|(repo_name, repo_packages)| {
-                repo_packages
-                    .collection
-                    .iter()
-                    .filter(|(key, _)| collection.is_none() || Some(key.as_str()) == collection)
-                    .flat_map(|(key, collections)| {
-                        collections.iter().flat_map(|(_, packages)| {
-                            packages.iter().map(|package| ResolvedPackage {
-                                repo_name: repo_name.clone(),
-                                collection: key.clone(),
-                                package: package.clone(),
-                            })
-                        })
-                    })
-            })
-            .collect();
-
-        packages.sort_by(|a, b| {
-            let collection_cmp = a.collection.cmp(&b.collection);
-            if collection_cmp == std::cmp::Ordering::Equal {
-                a.package.full_name('-').cmp(&b.package.full_name('-'))
-            } else {
-                collection_cmp
-            }
-        });
-        packages
+        let sql = format!(
+            "{} WHERE (?1 IS NULL OR c.name = ?1)
+            ORDER BY c.name, (CASE WHEN f.name = '' THEN '' ELSE f.name || '-' END) || p.pkg",
+            PACKAGE_QUERY
+        );
+        self.query_packages(&sql, params![collection], |_| Ok(()))
+            .into_iter()
+            .map(|(package, _)| package)
+            .collect()
     }
 
     pub fn get_packages(&self, query: &PackageQuery) -> Option<Vec<ResolvedPackage>> {
         let pkg_name = query.name.trim();
+        let sql = format!(
+            "{} WHERE LOWER(p.pkg) = ?1
+            AND (?2 IS NULL OR f.name = ?2)
+            AND (?3 IS NULL OR c.name = ?3)",
+            PACKAGE_QUERY
+        );
         let resolved_packages: Vec<ResolvedPackage> = self
-            .repository
-            .iter()
-            .flat_map(|(repo_name, packages)| {
-                packages
-                    .collection
-                    .iter()
-                    .filter(|(collection_key, _)| {
-                        query.collection.is_none()
-                            || Some(collection_key.as_str()) == query.collection.as_deref()
-                    })
-                    .flat_map(|(collection_key, map)| {
-                        map.get(pkg_name).into_iter().flat_map(|pkgs| {
-                            pkgs.iter().filter_map(|pkg| {
-                                if pkg.pkg.to_lowercase() == pkg_name
-                                    && (query.family.is_none()
-                                        || pkg.family.as_ref() == query.family.as_ref())
-                                {
-                                    Some(ResolvedPackage {
-                                        repo_name: repo_name.to_owned(),
-                                        package: pkg.clone(),
-                                        collection: collection_key.clone(),
-                                    })
-                                } else {
-                                    None
-                                }
-                            })
-                        })
-                    })
-            })
+            .query_packages(
+                &sql,
+                params![pkg_name, query.family, query.collection],
+                |_| Ok(()),
+            )
+            .into_iter()
+            .map(|(package, _)| package)
+            .filter(|package| query.matches_repo(&package.repo_name))
             .collect();
 
         if !resolved_packages.is_empty() {
@@ -384,65 +401,41 @@ impl PackageStorage {
         } else {
             query.name.trim().to_lowercase()
         };
-        let mut resolved_packages: Vec<(u32, Package, String, String)> = Vec::new();
+        let (name_column, description_column) = if case_sensitive {
+            ("pkg", "description")
+        } else {
+            ("LOWER(pkg)", "LOWER(description)")
+        };
 
-        for (repo_name, packages) in &self.repository {
-            for (collection_name, collection_packages) in &packages.collection {
-                let pkgs: Vec<(u32, Package, String, String)> = collection_packages
-                    .iter()
-                    .flat_map(|(_, packages)| {
-                        packages.iter().filter_map(|pkg| {
-                            let mut score = 0;
-                            let (found_pkg_name, found_pkg_description) = if case_sensitive {
-                                (pkg.pkg.clone(), pkg.description.clone())
-                            } else {
-                                (pkg.pkg.to_lowercase(), pkg.description.to_lowercase())
-                            };
+        let sql = format!(
+            "SELECT * FROM (
+                SELECT *, CASE
+                    WHEN {0} = ?1 THEN 5
+                    WHEN instr({0}, ?1) > 0 THEN 3
+                    WHEN instr({1}, ?1) > 0 THEN 1
+                    ELSE 0
+                END AS score
+                FROM ({2})
+            ) WHERE score > 0 AND (?2 IS NULL OR family = ?2)",
+            name_column, description_column, PACKAGE_QUERY
+        );
 
-                            if found_pkg_name == pkg_name {
-                                score += 5;
-                            } else if found_pkg_name.contains(&pkg_name) {
-                                score += 3;
-                            } else if found_pkg_description.contains(&pkg_name) {
-                                score += 1;
-                            } else {
-                                return None;
-                            }
-                            if query.family.is_none()
-                                || pkg.family.as_ref() == query.family.as_ref()
-                            {
-                                Some((
-                                    score,
-                                    pkg.to_owned(),
-                                    collection_name.to_owned(),
-                                    repo_name.to_owned(),
-                                ))
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                    .collect();
-                resolved_packages.extend(pkgs);
-            }
-        }
+        let mut resolved_packages =
+            self.query_packages(&sql, params![pkg_name, query.family], |row| {
+                row.get::<_, u32>("score")
+            });
 
-        resolved_packages.sort_by(|(a, _, _, _), (b, _, _, _)| b.cmp(a));
+        resolved_packages.sort_by(|(_, a), (_, b)| b.cmp(a));
         resolved_packages
             .into_iter()
-            .filter(|(score, _, _, _)| *score > 0)
-            .map(|(_, pkg, collection, repo_name)| ResolvedPackage {
-                repo_name,
-                package: pkg,
-                collection,
-            })
+            .map(|(package, _)| package)
+            .filter(|package| query.matches_repo(&package.repo_name))
             .collect()
     }
 
     pub async fn inspect(&self, package_name: &str, inspect_type: &str) -> Result<()> {
         let resolved_pkg = self.resolve_package(package_name, false)?;
 
-        let client = reqwest::Client::new();
         let url = if inspect_type == "log" {
             resolved_pkg.package.build_log
         } else if resolved_pkg
@@ -459,7 +452,7 @@ impl PackageStorage {
             resolved_pkg.package.build_script
         };
 
-        let response = client.get(&url).send().await?;
+        let response = CLIENT.get(&url).send().await?;
 
         if !response.status().is_success() {
             return Err(anyhow::anyhow!(
@@ -504,7 +497,13 @@ impl PackageStorage {
         Ok(())
     }
 
-    pub async fn run(&self, command: &[String], yes: bool) -> Result<()> {
+    pub async fn run(
+        &self,
+        command: &[String],
+        yes: bool,
+        installed_packages: Arc<Mutex<InstalledPackages>>,
+        allow_checksum_mismatch: bool,
+    ) -> Result<()> {
         fs::create_dir_all(&*CACHE_PATH).await?;
 
         let package_name = &command[0];
@@ -513,28 +512,30 @@ impl PackageStorage {
         } else {
             &[]
         };
-        let runner = if let Ok(resolved_pkg) = self.resolve_package(package_name, yes) {
-            let package_path = CACHE_PATH.join(&resolved_pkg.package.pkg_name);
-            Runner::new(&resolved_pkg, package_path, args)
+        let resolved_pkg = if let Ok(resolved_pkg) = self.resolve_package(package_name, yes) {
+            resolved_pkg
         } else {
             let query = parse_package_query(package_name);
-            let package_path = CACHE_PATH.join(&query.name);
             let mut resolved_pkg = ResolvedPackage::default();
-            resolved_pkg.package.pkg = query.name;
-            resolved_pkg.package.family = query.family;
+            resolved_pkg.package.pkg = query.name.clone();
+            resolved_pkg.package.pkg_name = query.name.clone();
+            resolved_pkg.package.family = query.family.clone();
 
             // TODO: check all the repo for package instead of choosing the first
-            let base_url = CONFIG
+            let (repo_name, base_url) = CONFIG
                 .repositories
                 .iter()
+                .filter(|repo| query.matches_repo(&repo.name))
                 .find_map(|repo| {
                     if let Some(collection) = &query.collection {
-                        repo.sources.get(collection).cloned()
+                        repo.sources.get(collection)
                     } else {
-                        repo.sources.values().next().cloned()
+                        repo.sources.values().next()
                     }
+                    .map(|mirrors| (repo.name.clone(), mirrors.primary().to_owned()))
                 })
                 .ok_or_else(|| anyhow::anyhow!("No repository found for the package"))?;
+            resolved_pkg.repo_name = repo_name;
 
             resolved_pkg.collection = query.collection.unwrap_or_else(|| {
                 CONFIG
@@ -546,9 +547,21 @@ impl PackageStorage {
 
             let download_url = format!("{}/{}", base_url, resolved_pkg.package.full_name('/'));
             resolved_pkg.package.download_url = download_url;
-            Runner::new(&resolved_pkg, package_path, args)
+            resolved_pkg
         };
 
+        let package_path = CACHE_PATH.join(&resolved_pkg.package.pkg_name);
+        let sandbox = installed_packages
+            .lock()
+            .await
+            .sandbox_rules(&resolved_pkg)?;
+        let runner = Runner::new(
+            &resolved_pkg,
+            package_path,
+            args,
+            sandbox,
+            allow_checksum_mismatch,
+        );
         runner.execute().await?;
 
         Ok(())